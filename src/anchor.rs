@@ -0,0 +1,641 @@
+use clap::ArgMatches;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::io::{BufRead, Write};
+use std::path::Path;
+
+use crate::config::ProbT;
+
+/// field delimiter of a table, tabs or commas if present in the line,
+/// whitespace otherwise.
+pub fn get_delimiter(line: &str) -> Option<char> {
+    if line.contains('\t') {
+        Some('\t')
+    } else if line.contains(',') {
+        Some(',')
+    } else {
+        None
+    }
+}
+
+/// fields of a table line, with the quotes removed.
+pub fn split_fields(line: &str, delimiter: Option<char>) -> Vec<&str> {
+    let toks: Vec<&str> = match delimiter {
+        Some(delimiter) => line.split(delimiter).map(|x| x.trim()).collect(),
+        None => line.split_whitespace().collect(),
+    };
+
+    toks.iter().map(|x| x.trim_matches('"')).collect()
+}
+
+/// how the scores of the anchors of a reference cell are turned into the
+/// weights of the query cells in its imputed profile.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum WeightScheme {
+    /// the anchor scores as is.
+    Raw,
+    /// the scores divided by the number of anchors of the reference cell.
+    Mean,
+    /// the scores divided by their sum over the reference cell.
+    Normalized,
+    /// softmax of the scores of the reference cell with the temperature.
+    Softmax(ProbT),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct AnchorWeighting {
+    pub scheme: WeightScheme,
+    /// anchors with a lower score are dropped.
+    pub min_score: ProbT,
+    /// only the top scoring anchors of every reference cell are kept.
+    pub top_k: Option<usize>,
+}
+
+impl Default for AnchorWeighting {
+    fn default() -> AnchorWeighting {
+        AnchorWeighting {
+            scheme: WeightScheme::Mean,
+            min_score: 0.0,
+            top_k: None,
+        }
+    }
+}
+
+impl AnchorWeighting {
+    pub fn from_clap(sub_m: &ArgMatches) -> Result<AnchorWeighting, Box<dyn Error>> {
+        let scheme = match sub_m.value_of("anchor_weighting").unwrap_or("mean") {
+            "raw" => WeightScheme::Raw,
+            "mean" => WeightScheme::Mean,
+            "normalized" => WeightScheme::Normalized,
+            "softmax" => {
+                let temperature = sub_m
+                    .value_of("softmax_temperature")
+                    .unwrap_or("1")
+                    .parse::<ProbT>()?;
+                if temperature <= 0.0 {
+                    return Err(format!("softmax temperature {} should be > 0", temperature).into());
+                }
+                WeightScheme::Softmax(temperature)
+            }
+            scheme => return Err(format!("unknown anchor weighting {}", scheme).into()),
+        };
+
+        let top_k = match sub_m.value_of("top_k_anchors") {
+            Some(val) => Some(val.parse::<usize>()?),
+            None => None,
+        };
+        if top_k == Some(0) {
+            return Err("top_k_anchors should be > 0".into());
+        }
+
+        Ok(AnchorWeighting {
+            scheme,
+            min_score: sub_m
+                .value_of("min_anchor_score")
+                .unwrap_or("0")
+                .parse::<ProbT>()?,
+            top_k,
+        })
+    }
+
+    fn get_weights(&self, scores: &[ProbT]) -> Vec<ProbT> {
+        match self.scheme {
+            WeightScheme::Raw => scores.to_vec(),
+            WeightScheme::Mean => {
+                let norm = scores.len() as ProbT;
+                scores.iter().map(|x| x / norm).collect()
+            }
+            WeightScheme::Normalized => {
+                let norm: ProbT = scores.iter().sum();
+                match norm > 0.0 {
+                    true => scores.iter().map(|x| x / norm).collect(),
+                    false => vec![0.0; scores.len()],
+                }
+            }
+            WeightScheme::Softmax(temperature) => {
+                // shifted by the max score for numerical stability.
+                let max_score = scores.iter().cloned().fold(ProbT::MIN, ProbT::max);
+                let exps: Vec<ProbT> = scores
+                    .iter()
+                    .map(|x| ((x - max_score) / temperature).exp())
+                    .collect();
+                let norm: ProbT = exps.iter().sum();
+                exps.into_iter().map(|x| x / norm).collect()
+            }
+        }
+    }
+
+    /// replaces the scores of the query cell -> reference cell anchors of an
+    /// assay with their weights, returns the number of dropped anchors.
+    pub fn apply(&self, anchor_triplets: &mut HashMap<u32, HashMap<u32, ProbT>>) -> usize {
+        let mut reference_anchors: HashMap<u32, Vec<(u32, ProbT)>> = HashMap::new();
+        let mut num_dropped = 0;
+        for (&cb, dict) in anchor_triplets.iter() {
+            for (&cell_id, &score) in dict {
+                if score < self.min_score {
+                    num_dropped += 1;
+                    continue;
+                }
+                reference_anchors
+                    .entry(cell_id)
+                    .or_insert_with(Vec::new)
+                    .push((cb, score));
+            }
+        }
+
+        anchor_triplets.clear();
+        for (cell_id, mut anchors) in reference_anchors {
+            if let Some(top_k) = self.top_k {
+                if anchors.len() > top_k {
+                    // ties broken by the query cell for reproducibility,
+                    // the scores are finite as checked by `hmm::parse_anchor`.
+                    anchors.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap().then(a.0.cmp(&b.0)));
+                    num_dropped += anchors.len() - top_k;
+                    anchors.truncate(top_k);
+                }
+            }
+
+            let scores: Vec<ProbT> = anchors.iter().map(|x| x.1).collect();
+            for ((cb, _), weight) in anchors.into_iter().zip(self.get_weights(&scores)) {
+                anchor_triplets
+                    .entry(cb)
+                    .or_insert_with(HashMap::new)
+                    .insert(cell_id, weight);
+            }
+        }
+
+        num_dropped
+    }
+}
+
+////////////////////////////////////////////
+/// Embedding
+/// cell names and their coordinates in a shared low dimensional space,
+/// one cell per line with an optional header.
+////////////////////////////////////////////
+struct Embedding {
+    cells: Vec<String>,
+    coords: Vec<Vec<ProbT>>,
+}
+
+impl Embedding {
+    fn from_path(path: &Path) -> Result<Embedding, Box<dyn Error>> {
+        let reader = carina::file::bufreader_from_filepath(path.to_path_buf())?;
+
+        let (mut delimiter, mut num_records) = (None, 0);
+        let (mut cells, mut coords) = (Vec::new(), Vec::new());
+        for (line_num, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            num_records += 1;
+            if num_records == 1 {
+                delimiter = get_delimiter(&line);
+            }
+
+            let toks = split_fields(&line, delimiter);
+            let cell_coords: Result<Vec<ProbT>, _> =
+                toks.iter().skip(1).map(|x| x.parse::<ProbT>()).collect();
+            let cell_coords = match cell_coords {
+                Ok(cell_coords) if !cell_coords.is_empty() => cell_coords,
+                // the first line can be a header.
+                _ if num_records == 1 => continue,
+                _ => {
+                    return Err(format!(
+                        "{:?} line {}: can't parse coordinates",
+                        path,
+                        line_num + 1
+                    )
+                    .into())
+                }
+            };
+            if cell_coords.iter().any(|x| !x.is_finite()) {
+                return Err(
+                    format!("{:?} line {}: non finite coordinate", path, line_num + 1).into(),
+                );
+            }
+            let num_dims = coords
+                .first()
+                .map_or(cell_coords.len(), |x: &Vec<ProbT>| x.len());
+            if num_dims != cell_coords.len() {
+                return Err(format!(
+                    "{:?} line {}: expected {} dimensions, found {}",
+                    path,
+                    line_num + 1,
+                    num_dims,
+                    cell_coords.len()
+                )
+                .into());
+            }
+
+            cells.push(toks[0].to_string());
+            coords.push(cell_coords);
+        }
+
+        if cells.is_empty() {
+            return Err(format!("no cells found in {:?}", path).into());
+        }
+
+        Ok(Embedding { cells, coords })
+    }
+
+    fn num_dims(&self) -> usize {
+        self.coords[0].len()
+    }
+}
+
+fn get_distance(a: &[ProbT], b: &[ProbT]) -> ProbT {
+    a.iter()
+        .zip(b.iter())
+        .map(|(x, y)| (x - y) * (x - y))
+        .sum::<ProbT>()
+        .sqrt()
+}
+
+/// k nearest (index, distance) of the point among the candidates.
+fn get_nearest(
+    point: &[ProbT],
+    coords: &[Vec<ProbT>],
+    candidates: impl Iterator<Item = usize>,
+    k: usize,
+) -> Vec<(usize, ProbT)> {
+    let mut neighbours: Vec<(usize, ProbT)> = candidates
+        .map(|i| (i, get_distance(point, &coords[i])))
+        .collect();
+    // distances are never NaN, the coordinates being finite.
+    let by_distance = |a: &(usize, ProbT), b: &(usize, ProbT)| a.1.partial_cmp(&b.1).unwrap();
+    if neighbours.len() > k {
+        neighbours.select_nth_unstable_by(k - 1, by_distance);
+        neighbours.truncate(k);
+    }
+    neighbours.sort_by(by_distance);
+
+    neighbours
+}
+
+/// node of a random projection tree, splitting the points by the side of
+/// a random hyperplane.
+enum RpNode {
+    Leaf(Vec<usize>),
+    Split {
+        normal: Vec<ProbT>,
+        offset: ProbT,
+        left: usize,
+        right: usize,
+    },
+}
+
+////////////////////////////////////////////
+/// Random Projection Tree
+/// approximate nearest neighbours of a point are searched in the leaf
+/// it falls in, over a forest of trees.
+////////////////////////////////////////////
+struct RpTree {
+    nodes: Vec<RpNode>,
+}
+
+impl RpTree {
+    fn new(coords: &[Vec<ProbT>], leaf_size: usize, rng: &mut StdRng) -> RpTree {
+        let mut tree = RpTree { nodes: Vec::new() };
+        tree.build((0..coords.len()).collect(), coords, leaf_size, rng);
+
+        tree
+    }
+
+    fn build(
+        &mut self,
+        indices: Vec<usize>,
+        coords: &[Vec<ProbT>],
+        leaf_size: usize,
+        rng: &mut StdRng,
+    ) -> usize {
+        let node_id = self.nodes.len();
+        if indices.len() <= leaf_size {
+            self.nodes.push(RpNode::Leaf(indices));
+            return node_id;
+        }
+
+        // hyperplane equidistant to two random points.
+        let a = &coords[indices[rng.gen_range(0..indices.len())]];
+        let b = &coords[indices[rng.gen_range(0..indices.len())]];
+        let normal: Vec<ProbT> = a.iter().zip(b.iter()).map(|(x, y)| x - y).collect();
+        let offset: ProbT = normal
+            .iter()
+            .zip(a.iter().zip(b.iter()))
+            .map(|(n, (x, y))| n * (x + y) / 2.0)
+            .sum();
+
+        let (left, right): (Vec<usize>, Vec<usize>) = indices
+            .iter()
+            .partition(|&&i| RpTree::side(&normal, offset, &coords[i]));
+        if left.is_empty() || right.is_empty() {
+            // duplicated points, can't be split further.
+            self.nodes.push(RpNode::Leaf(indices));
+            return node_id;
+        }
+
+        self.nodes.push(RpNode::Leaf(Vec::new()));
+        let left = self.build(left, coords, leaf_size, rng);
+        let right = self.build(right, coords, leaf_size, rng);
+        self.nodes[node_id] = RpNode::Split {
+            normal,
+            offset,
+            left,
+            right,
+        };
+
+        node_id
+    }
+
+    fn side(normal: &[ProbT], offset: ProbT, point: &[ProbT]) -> bool {
+        normal
+            .iter()
+            .zip(point.iter())
+            .map(|(n, x)| n * x)
+            .sum::<ProbT>()
+            < offset
+    }
+
+    fn leaf(&self, point: &[ProbT]) -> &[usize] {
+        let mut node_id = 0;
+        loop {
+            match &self.nodes[node_id] {
+                RpNode::Leaf(indices) => return indices,
+                RpNode::Split {
+                    normal,
+                    offset,
+                    left,
+                    right,
+                } => {
+                    node_id = match RpTree::side(normal, *offset, point) {
+                        true => *left,
+                        false => *right,
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// how the nearest query cells of a reference cell are searched.
+#[derive(Debug, Clone, Copy)]
+enum KnnMethod {
+    Exact,
+    /// random projection forest with the number of trees.
+    Approximate(usize),
+}
+
+/// k nearest query cells of every reference cell.
+fn get_knn(
+    reference: &Embedding,
+    query: &Embedding,
+    k: usize,
+    method: KnnMethod,
+    seed: u64,
+    num_threads: usize,
+) -> Vec<Vec<(usize, ProbT)>> {
+    let forest: Vec<RpTree> = match method {
+        KnnMethod::Exact => Vec::new(),
+        KnnMethod::Approximate(num_trees) => {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let leaf_size = std::cmp::max(2 * k, 32);
+            (0..num_trees)
+                .map(|_| RpTree::new(&query.coords, leaf_size, &mut rng))
+                .collect()
+        }
+    };
+
+    let search = |point: &[ProbT]| match method {
+        KnnMethod::Exact => get_nearest(point, &query.coords, 0..query.coords.len(), k),
+        KnnMethod::Approximate(_) => {
+            let candidates: HashSet<usize> = forest
+                .iter()
+                .flat_map(|tree| tree.leaf(point).iter().copied())
+                .collect();
+            get_nearest(point, &query.coords, candidates.into_iter(), k)
+        }
+    };
+
+    let chunk_size = (reference.coords.len() + num_threads - 1) / num_threads;
+    let mut knn = Vec::with_capacity(reference.coords.len());
+    crossbeam::scope(|scope| {
+        let handles: Vec<_> = reference
+            .coords
+            .chunks(chunk_size)
+            .map(|chunk| {
+                let search = &search;
+                scope.spawn(move |_| chunk.iter().map(|x| search(x)).collect::<Vec<_>>())
+            })
+            .collect();
+
+        handles
+            .into_iter()
+            .for_each(|handle| knn.extend(handle.join().unwrap()));
+    })
+    .unwrap();
+
+    knn
+}
+
+pub fn callback(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let num_threads: usize = sub_m.value_of("threads").unwrap_or("1").parse()?;
+    let k: usize = sub_m.value_of("neighbors").unwrap_or("10").parse()?;
+    if k == 0 || num_threads == 0 {
+        return Err("neighbors and threads should be > 0".into());
+    }
+    let seed: u64 = sub_m.value_of("seed").unwrap_or("0").parse()?;
+    let method = match sub_m.value_of("method").unwrap_or("exact") {
+        "exact" => KnnMethod::Exact,
+        "approximate" => KnnMethod::Approximate(
+            sub_m
+                .value_of("num_trees")
+                .unwrap_or("10")
+                .parse::<usize>()?,
+        ),
+        method => return Err(format!("unknown kNN method {}", method).into()),
+    };
+
+    let reference = Embedding::from_path(&carina::file::file_path_from_clap(sub_m, "reference")?)?;
+    let query = Embedding::from_path(&carina::file::file_path_from_clap(sub_m, "query")?)?;
+    if reference.num_dims() != query.num_dims() {
+        return Err(format!(
+            "reference has {} dimensions, query has {}",
+            reference.num_dims(),
+            query.num_dims()
+        )
+        .into());
+    }
+    info!(
+        "Found {} reference and {} query cells with {} dimensions",
+        reference.cells.len(),
+        query.cells.len(),
+        reference.num_dims()
+    );
+
+    info!(
+        "Searching {} nearest query cells with {:?} method",
+        k, method
+    );
+    let knn = get_knn(&reference, &query, k, method, seed, num_threads);
+
+    // gaussian kernel with the distance of the farthest neighbour of the
+    // reference cell as the bandwidth.
+    let out_path = std::path::PathBuf::from(sub_m.value_of("output").unwrap());
+    let mut file = std::io::BufWriter::new(std::fs::File::create(&out_path)?);
+    let mut num_anchors = 0;
+    for (cell, neighbours) in reference.cells.iter().zip(knn) {
+        let bandwidth = neighbours.last().map_or(0.0, |x| x.1);
+        for (query_id, distance) in neighbours {
+            let score = match bandwidth > 0.0 {
+                true => (-(distance / bandwidth).powi(2)).exp(),
+                false => 1.0,
+            };
+            writeln!(file, "{}\t{}\t{:.6}", cell, query.cells[query_id], score)?;
+            num_anchors += 1;
+        }
+    }
+    info!("Wrote {} anchors to {:?}", num_anchors, out_path);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_triplets(anchors: &[(u32, u32, ProbT)]) -> HashMap<u32, HashMap<u32, ProbT>> {
+        let mut anchor_triplets: HashMap<u32, HashMap<u32, ProbT>> = HashMap::new();
+        for &(cb, cell_id, score) in anchors {
+            anchor_triplets
+                .entry(cb)
+                .or_insert_with(HashMap::new)
+                .insert(cell_id, score);
+        }
+        anchor_triplets
+    }
+
+    /// (query cell, weight) of the anchors of a reference cell.
+    fn get_weights(
+        anchor_triplets: &HashMap<u32, HashMap<u32, ProbT>>,
+        cell_id: u32,
+    ) -> Vec<(u32, ProbT)> {
+        let mut weights: Vec<(u32, ProbT)> = anchor_triplets
+            .iter()
+            .filter_map(|(&cb, x)| x.get(&cell_id).map(|&weight| (cb, weight)))
+            .collect();
+        weights.sort_by_key(|x| x.0);
+        weights
+    }
+
+    #[test]
+    fn test_weighting_schemes() {
+        let anchors = [(1, 0, 1.0), (2, 0, 3.0), (1, 1, 2.0)];
+        let apply = |scheme: WeightScheme| {
+            let weighting = AnchorWeighting {
+                scheme,
+                ..AnchorWeighting::default()
+            };
+            let mut anchor_triplets = get_triplets(&anchors);
+            assert_eq!(weighting.apply(&mut anchor_triplets), 0);
+            (
+                get_weights(&anchor_triplets, 0),
+                get_weights(&anchor_triplets, 1),
+            )
+        };
+
+        assert_eq!(
+            apply(WeightScheme::Raw),
+            (vec![(1, 1.0), (2, 3.0)], vec![(1, 2.0)])
+        );
+        assert_eq!(
+            apply(WeightScheme::Mean),
+            (vec![(1, 0.5), (2, 1.5)], vec![(1, 2.0)])
+        );
+        assert_eq!(
+            apply(WeightScheme::Normalized),
+            (vec![(1, 0.25), (2, 0.75)], vec![(1, 1.0)])
+        );
+
+        // the weights sum to one, and the higher the temperature the closer
+        // they get to uniform.
+        for &temperature in &[0.5, 1.0, 4.0] {
+            let (weights, single) = apply(WeightScheme::Softmax(temperature));
+            assert_eq!(single, [(1, 1.0)]);
+            assert!((weights[0].1 + weights[1].1 - 1.0).abs() < 1e-6);
+            let ratio = weights[1].1 / weights[0].1;
+            let expected = (2.0 / temperature).exp();
+            assert!(
+                (ratio - expected).abs() < 1e-4 * expected,
+                "{}",
+                temperature
+            );
+        }
+    }
+
+    #[test]
+    fn test_weighting_filters() {
+        let anchors = [
+            (1, 0, 1.0),
+            (2, 0, 2.0),
+            (3, 0, 2.0),
+            (4, 0, 0.1),
+            (5, 0, 3.0),
+            (4, 1, 0.2),
+        ];
+        let apply = |min_score: ProbT, top_k: Option<usize>| {
+            let weighting = AnchorWeighting {
+                scheme: WeightScheme::Raw,
+                min_score,
+                top_k,
+            };
+            let mut anchor_triplets = get_triplets(&anchors);
+            let num_dropped = weighting.apply(&mut anchor_triplets);
+            (num_dropped, get_weights(&anchor_triplets, 0))
+        };
+
+        // the low scoring anchors are dropped before the top ones are
+        // picked, ties going to the lowest query cell.
+        assert_eq!(
+            apply(0.5, None),
+            (2, vec![(1, 1.0), (2, 2.0), (3, 2.0), (5, 3.0)])
+        );
+        assert_eq!(apply(0.5, Some(2)), (4, vec![(2, 2.0), (5, 3.0)]));
+        assert_eq!(
+            apply(0.0, Some(4)),
+            (1, vec![(1, 1.0), (2, 2.0), (3, 2.0), (5, 3.0)])
+        );
+        assert_eq!(apply(0.0, Some(1)).1, [(5, 3.0)]);
+
+        // reference cells without any anchor left are gone.
+        let weighting = AnchorWeighting {
+            scheme: WeightScheme::Raw,
+            min_score: 0.5,
+            top_k: None,
+        };
+        let mut anchor_triplets = get_triplets(&anchors);
+        weighting.apply(&mut anchor_triplets);
+        assert!(get_weights(&anchor_triplets, 1).is_empty());
+        assert!(!anchor_triplets.contains_key(&4));
+    }
+
+    #[test]
+    fn test_embedding_header() {
+        let path = std::env::temp_dir().join("schrom_test_embedding.csv");
+        std::fs::write(&path, "\ncell,x,y\nr1,0.5,1\n\nr2,1,2.5\n").unwrap();
+        let embedding = Embedding::from_path(&path).unwrap();
+        assert_eq!(embedding.cells, ["r1", "r2"]);
+        assert_eq!(embedding.coords, [vec![0.5, 1.0], vec![1.0, 2.5]]);
+
+        // only the first line can be a header.
+        std::fs::write(&path, "\nr1 0.5 1\ncell x y\n").unwrap();
+        let err = Embedding::from_path(&path).err().unwrap().to_string();
+        assert!(err.contains("line 3"), "{}", err);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
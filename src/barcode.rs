@@ -0,0 +1,158 @@
+use clap::ArgMatches;
+use std::collections::HashMap;
+
+/// how the cellular barcodes of the fragments are matched against the
+/// barcodes of the anchors.
+#[derive(Debug, Clone, Default)]
+pub struct BarcodeOptions {
+    prefix: String,
+    suffix: String,
+    strip_suffix: bool,
+}
+
+impl BarcodeOptions {
+    pub fn from_clap(sub_m: &ArgMatches) -> BarcodeOptions {
+        BarcodeOptions {
+            prefix: sub_m.value_of("barcode_prefix").unwrap_or("").to_string(),
+            suffix: sub_m.value_of("barcode_suffix").unwrap_or("").to_string(),
+            strip_suffix: sub_m.is_present("strip_barcode_suffix"),
+        }
+    }
+
+    /// removes the trailing `-<suffix>` of a barcode, if enabled.
+    fn strip<'a>(&self, barcode: &'a str) -> &'a str {
+        match self.strip_suffix {
+            true => barcode.rsplitn(2, '-').last().unwrap(),
+            false => barcode,
+        }
+    }
+
+    pub fn anchor_barcode(&self, barcode: &str) -> String {
+        self.strip(barcode).to_string()
+    }
+}
+
+////////////////////////////////////////////
+/// Barcode Table
+/// interns the barcodes of the anchored query cells of an assay to dense
+/// ids, barcodes absent from the table are not anchored.
+////////////////////////////////////////////
+#[derive(Debug, Clone, Default)]
+pub struct BarcodeTable {
+    options: BarcodeOptions,
+    ids: HashMap<String, u32>,
+    names: Vec<String>,
+}
+
+impl BarcodeTable {
+    pub fn new(options: BarcodeOptions) -> BarcodeTable {
+        BarcodeTable {
+            options,
+            ids: HashMap::new(),
+            names: Vec::new(),
+        }
+    }
+
+    /// id of an anchor barcode, added to the table if not present.
+    pub fn intern(&mut self, barcode: &str) -> u32 {
+        let barcode = self.options.anchor_barcode(barcode);
+        if let Some(&id) = self.ids.get(&barcode) {
+            return id;
+        }
+
+        let id = self.names.len() as u32;
+        self.names.push(barcode.clone());
+        self.ids.insert(barcode, id);
+        id
+    }
+
+    pub fn num_barcodes(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn name(&self, id: u32) -> &str {
+        &self.names[id as usize]
+    }
+
+    /// id of a fragment barcode, optionally stripped and then wrapped with
+    /// the prefix and the suffix; `None` if the cell is not anchored.
+    /// The suffix of the library, if any, replaces the common suffix.
+    pub fn get(&self, barcode: &str, library_suffix: Option<&str>) -> Option<u32> {
+        let barcode = self.options.strip(barcode);
+        let suffix = library_suffix.unwrap_or(&self.options.suffix);
+        let id = match self.options.prefix.is_empty() && suffix.is_empty() {
+            true => self.ids.get(barcode),
+            false => self
+                .ids
+                .get(&format!("{}{}{}", self.options.prefix, barcode, suffix)),
+        };
+
+        id.copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_table(options: BarcodeOptions, barcodes: &[&str]) -> BarcodeTable {
+        let mut table = BarcodeTable::new(options);
+        barcodes.iter().for_each(|x| {
+            table.intern(x);
+        });
+        table
+    }
+
+    #[test]
+    fn test_get_exact() {
+        let table = get_table(BarcodeOptions::default(), &["AAA-1", "CCC-1", "AAA-1"]);
+        assert_eq!(table.num_barcodes(), 2);
+        assert_eq!(table.get("AAA-1", None), Some(0));
+        assert_eq!(table.get("CCC-1", None), Some(1));
+        assert_eq!(table.get("AAA", None), None);
+        assert_eq!(table.get("AAA-2", None), None);
+
+        // the library suffix is appended even without a common one.
+        assert_eq!(table.get("AAA", Some("-1")), Some(0));
+        assert_eq!(table.get("AAA-1", Some("-1")), None);
+    }
+
+    #[test]
+    fn test_get_prefix_suffix() {
+        let options = BarcodeOptions {
+            prefix: "s1_".to_string(),
+            suffix: "-1".to_string(),
+            strip_suffix: false,
+        };
+        let table = get_table(options, &["s1_AAA-1", "s1_CCC-2"]);
+        assert_eq!(table.get("AAA", None), Some(0));
+        assert_eq!(table.get("AAA-1", None), None);
+        assert_eq!(table.get("CCC", None), None);
+
+        // the library suffix replaces the common one.
+        assert_eq!(table.get("CCC", Some("-2")), Some(1));
+        assert_eq!(table.get("AAA", Some("-2")), None);
+    }
+
+    #[test]
+    fn test_get_strip() {
+        let options = BarcodeOptions {
+            prefix: String::new(),
+            suffix: String::new(),
+            strip_suffix: true,
+        };
+        let table = get_table(options.clone(), &["AAA-1", "CCC", "GGG-1-2"]);
+        assert_eq!(table.name(0), "AAA");
+        assert_eq!(table.name(2), "GGG-1");
+        assert_eq!(table.get("AAA-2", None), Some(0));
+        assert_eq!(table.get("AAA", None), Some(0));
+        assert_eq!(table.get("CCC-1", None), Some(1));
+        assert_eq!(table.get("GGG-1-1", None), Some(2));
+        assert_eq!(table.get("GGG-1", None), None);
+
+        // stripped before the suffix of the library is added.
+        let table = get_table(options, &["AAA-1"]);
+        assert_eq!(table.get("AAA-9", Some("-1")), None);
+        assert_eq!(table.get("AAA-9", Some("")), Some(0));
+    }
+}
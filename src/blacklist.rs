@@ -0,0 +1,65 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::BufRead;
+use std::ops::Range;
+use std::path::PathBuf;
+
+use crate::config::{RangeT, WINDOW_SIZE};
+use crate::file;
+
+////////////////////////////////////////////
+/// Blacklist
+/// regions of a BED file (plain or gzipped), the bins overlapping them
+/// are masked from the observations.
+////////////////////////////////////////////
+#[derive(Debug, Default)]
+pub struct Blacklist {
+    regions: HashMap<String, Vec<Range<RangeT>>>,
+}
+
+impl Blacklist {
+    pub fn from_path(path: PathBuf) -> Result<Blacklist, Box<dyn Error>> {
+        let reader = file::bufreader_from_maybe_gz(&path)?;
+
+        let mut regions: HashMap<String, Vec<Range<RangeT>>> = HashMap::new();
+        for (line_num, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.is_empty() || line.starts_with('#') || line.starts_with("track") {
+                continue;
+            }
+
+            let toks: Vec<&str> = line.split_whitespace().collect();
+            let parse_error = || format!("can't parse line {} of {:?}", line_num + 1, path);
+            if toks.len() < 3 {
+                return Err(parse_error().into());
+            }
+            let start = toks[1].parse::<RangeT>().map_err(|_| parse_error())?;
+            let end = toks[2].parse::<RangeT>().map_err(|_| parse_error())?;
+
+            regions
+                .entry(toks[0].to_string())
+                .or_insert_with(Vec::new)
+                .push(Range { start, end });
+        }
+
+        Ok(Blacklist { regions })
+    }
+
+    pub fn num_regions(&self) -> usize {
+        self.regions.values().map(|x| x.len()).sum()
+    }
+
+    /// flags the bins of the chromosome overlapping a blacklisted region.
+    pub fn masked_bins(&self, chr_name: &str, num_bins: usize) -> Vec<bool> {
+        let mut masked = vec![false; num_bins];
+        if let Some(regions) = self.regions.get(chr_name) {
+            for region in regions.iter().filter(|x| x.end > x.start) {
+                let start_bin = region.start as usize / WINDOW_SIZE;
+                let end_bin = std::cmp::min((region.end as usize - 1) / WINDOW_SIZE + 1, num_bins);
+                (start_bin..end_bin).for_each(|bin| masked[bin] = true);
+            }
+        }
+
+        masked
+    }
+}
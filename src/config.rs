@@ -3,6 +3,11 @@ pub type ProbT = f32;
 
 pub const MIN_PROB: ProbT = 1e-2;
 pub const WINDOW_SIZE: usize = 200;
+// observation code of the blacklisted bins, emitted with probability 1 by
+// every state so that the hmm carries the neighbouring evidence over them.
+pub const MISSING_CODE: u32 = u32::MAX;
+// cells per thread quantified before `impute` writes their entries.
+pub const IMPUTE_BATCH_CELLS: usize = 16;
 //pub static THRESHOLDS: &[ProbT] = &[0.000, 0.005, 0.005, 0.0023, 0.0038, 0.000];
 pub static THRESHOLDS: &[ProbT] = &[
     0.0007079458,
@@ -13,6 +18,9 @@ pub static THRESHOLDS: &[ProbT] = &[
     0.001,
 ];
 
+// states reported in the posterior output.
+pub static VALID_STATES: &[usize] = &[0, 1, 2, 3, 8, 9, 11];
+
 pub static CHR_LENS: &[u32] = &[
     248956422, 242193529, 198295559, 190214555, 181538259, 170805979, 159345973, 145138636,
     138394717, 133797422, 135086622, 133275309, 114364328, 107043718, 101991189, 90338345,
@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::Write;
+use std::path::Path;
+
+use crate::config::ProbT;
+
+/// written by `evaluate` in the output directory.
+pub const EVALUATION_FILE: &str = "evaluation.tsv";
+
+/// the predictions are scored at the resolution of the posterior output.
+const NUM_BUCKETS: usize = 101;
+
+////////////////////////////////////////////
+/// Score Histogram
+/// number of positive and negative bins per predicted probability,
+/// rounded to 0.01, enough to compute the AUROC and AUPRC over any
+/// number of bins and cells.
+////////////////////////////////////////////
+#[derive(Debug, Clone)]
+pub struct ScoreHistogram {
+    positives: Vec<u64>,
+    negatives: Vec<u64>,
+}
+
+impl Default for ScoreHistogram {
+    fn default() -> ScoreHistogram {
+        ScoreHistogram {
+            positives: vec![0; NUM_BUCKETS],
+            negatives: vec![0; NUM_BUCKETS],
+        }
+    }
+}
+
+impl ScoreHistogram {
+    pub fn add(&mut self, score: ProbT, label: bool) {
+        let bucket = (score.max(0.0).min(1.0) * (NUM_BUCKETS - 1) as ProbT).round() as usize;
+        match label {
+            true => self.positives[bucket] += 1,
+            false => self.negatives[bucket] += 1,
+        }
+    }
+
+    pub fn merge(&mut self, other: &ScoreHistogram) {
+        for (x, y) in self.positives.iter_mut().zip(other.positives.iter()) {
+            *x += y;
+        }
+        for (x, y) in self.negatives.iter_mut().zip(other.negatives.iter()) {
+            *x += y;
+        }
+    }
+
+    pub fn num_positives(&self) -> u64 {
+        self.positives.iter().sum()
+    }
+
+    pub fn num_negatives(&self) -> u64 {
+        self.negatives.iter().sum()
+    }
+
+    /// (true positives, false positives) at every cutoff, from the highest
+    /// score down, the bins of a bucket being tied.
+    fn get_curve(&self) -> Vec<(f64, f64)> {
+        let (mut tp, mut fp) = (0.0, 0.0);
+        (0..NUM_BUCKETS)
+            .rev()
+            .map(|bucket| {
+                tp += self.positives[bucket] as f64;
+                fp += self.negatives[bucket] as f64;
+                (tp, fp)
+            })
+            .collect()
+    }
+
+    /// area under the ROC curve, `None` without both positives and negatives.
+    pub fn auroc(&self) -> Option<f64> {
+        let (num_pos, num_neg) = (self.num_positives(), self.num_negatives());
+        if num_pos == 0 || num_neg == 0 {
+            return None;
+        }
+
+        let (mut area, mut prev) = (0.0, (0.0, 0.0));
+        for (tp, fp) in self.get_curve() {
+            area += (fp - prev.1) * (tp + prev.0) / 2.0;
+            prev = (tp, fp);
+        }
+
+        Some(area / (num_pos as f64 * num_neg as f64))
+    }
+
+    /// average precision, `None` without positives.
+    pub fn auprc(&self) -> Option<f64> {
+        let num_pos = self.num_positives();
+        if num_pos == 0 {
+            return None;
+        }
+
+        let (mut area, mut prev_tp) = (0.0, 0.0);
+        for (tp, fp) in self.get_curve() {
+            if tp > prev_tp {
+                area += (tp - prev_tp) * tp / (tp + fp);
+                prev_tp = tp;
+            }
+        }
+
+        Some(area / num_pos as f64)
+    }
+}
+
+pub fn format_metric(metric: Option<f64>) -> String {
+    match metric {
+        Some(x) => format!("{:.4}", x),
+        None => "NA".to_string(),
+    }
+}
+
+/// writes the metrics of every cell and mark, followed by the ones of
+/// all the cells together as cell `all`.
+pub fn write_report(
+    path: &Path,
+    marks: &[String],
+    cells: &[(String, Vec<ScoreHistogram>)],
+) -> Result<Vec<ScoreHistogram>, Box<dyn Error>> {
+    let mut overall = vec![ScoreHistogram::default(); marks.len()];
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    writeln!(file, "cell\tmark\tauroc\tauprc\tpositives\tbins")?;
+
+    let mut write_row = |cell: &str, mark: &str, hist: &ScoreHistogram| {
+        writeln!(
+            file,
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            cell,
+            mark,
+            format_metric(hist.auroc()),
+            format_metric(hist.auprc()),
+            hist.num_positives(),
+            hist.num_positives() + hist.num_negatives()
+        )
+    };
+
+    for (cell, hists) in cells {
+        for (i, hist) in hists.iter().enumerate() {
+            write_row(cell, &marks[i], hist)?;
+            overall[i].merge(hist);
+        }
+    }
+    for (i, hist) in overall.iter().enumerate() {
+        write_row("all", &marks[i], hist)?;
+    }
+
+    Ok(overall)
+}
+
+/// merges the histograms of the cells of a chromosome into the running ones.
+pub fn merge_cells(
+    total: &mut HashMap<usize, Vec<ScoreHistogram>>,
+    cells: Vec<(usize, Vec<ScoreHistogram>)>,
+) {
+    for (cell_id, hists) in cells {
+        match total.get_mut(&cell_id) {
+            Some(cell_hists) => cell_hists
+                .iter_mut()
+                .zip(hists.iter())
+                .for_each(|(x, y)| x.merge(y)),
+            None => {
+                total.insert(cell_id, hists);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_histogram(positives: &[ProbT], negatives: &[ProbT]) -> ScoreHistogram {
+        let mut hist = ScoreHistogram::default();
+        positives.iter().for_each(|&x| hist.add(x, true));
+        negatives.iter().for_each(|&x| hist.add(x, false));
+        hist
+    }
+
+    fn assert_close(metric: Option<f64>, expected: f64) {
+        let metric = metric.unwrap();
+        assert!((metric - expected).abs() < 1e-9, "{} vs {}", metric, expected);
+    }
+
+    #[test]
+    fn test_perfect_ranking() {
+        let hist = get_histogram(&[0.9, 0.8], &[0.1, 0.2, 0.3]);
+        assert_close(hist.auroc(), 1.0);
+        assert_close(hist.auprc(), 1.0);
+    }
+
+    #[test]
+    fn test_reversed_ranking() {
+        let hist = get_histogram(&[0.1, 0.2], &[0.8, 0.9]);
+        assert_close(hist.auroc(), 0.0);
+        // precisions 1/3 and 2/4 at the two positives.
+        assert_close(hist.auprc(), (1.0 / 3.0 + 2.0 / 4.0) / 2.0);
+    }
+
+    #[test]
+    fn test_all_ties() {
+        let hist = get_histogram(&[0.5, 0.5], &[0.5, 0.5, 0.5]);
+        assert_close(hist.auroc(), 0.5);
+        assert_close(hist.auprc(), 2.0 / 5.0);
+    }
+
+    #[test]
+    fn test_one_class() {
+        let hist = get_histogram(&[0.3, 0.7], &[]);
+        assert_eq!(hist.auroc(), None);
+        assert_close(hist.auprc(), 1.0);
+
+        let hist = get_histogram(&[], &[0.3, 0.7]);
+        assert_eq!(hist.auroc(), None);
+        assert_eq!(hist.auprc(), None);
+
+        let hist = ScoreHistogram::default();
+        assert_eq!(hist.auroc(), None);
+        assert_eq!(hist.auprc(), None);
+    }
+}
@@ -0,0 +1,36 @@
+use std::error::Error;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// buffered reader of a plain or gzipped (`.gz`) file.
+pub fn bufreader_from_maybe_gz(path: &Path) -> Result<Box<dyn std::io::BufRead>, Box<dyn Error>> {
+    let file = std::fs::File::open(path).map_err(|e| format!("can't open {:?}: {}", path, e))?;
+    let reader: Box<dyn std::io::BufRead> = match path.extension().and_then(|x| x.to_str()) {
+        Some("gz") => Box::new(std::io::BufReader::new(flate2::read::MultiGzDecoder::new(
+            file,
+        ))),
+        _ => Box::new(std::io::BufReader::new(file)),
+    };
+
+    Ok(reader)
+}
+
+/// writes to a temporary file renamed into place once complete, so that
+/// a file is either fully written or absent.
+pub fn write_binary(path: PathBuf, mat: Vec<u8>) -> Result<(), Box<dyn Error>> {
+    let mut tmp_path = path.clone().into_os_string();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let f = std::fs::File::create(&tmp_path)?;
+    //let mut file = GzEncoder::new(f, Compression::default());
+    let mut file = std::io::BufWriter::new(f);
+    //let mut file = std::io::BufWriter::new(snap::write::FrameEncoder::new(f));
+
+    // entries
+    file.write_all(&mat)?;
+    file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+
+    std::fs::rename(tmp_path, path)?;
+    Ok(())
+}
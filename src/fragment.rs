@@ -1,81 +1,710 @@
+use crate::barcode::BarcodeTable;
 use crate::config::ProbT;
-use crate::record::{CellRecords, Record};
+use crate::matrix::CountMatrix;
+use crate::record::{parse_fragment, AssayRecords, Record};
 use std::ops::Range;
 
+use clap::ArgMatches;
+use rust_htslib::bam::{self, record::Aux, Read as BamRead};
 use rust_htslib::tbx::{self, Read};
 use std::collections::HashMap;
+use std::error::Error;
+use std::io::Write;
 use std::path::PathBuf;
 
+pub const DEPTH_FACTORS_FILE: &str = "depth_factors.tsv";
+
+/// alignments are fetched this far upstream of a region so that the leftmost
+/// mate of the pairs overlapping it is seen, longer than any read.
+const BAM_FETCH_PADDING: u32 = 1000;
+
+/// filters for building fragments out of BAM/CRAM alignments.
+#[derive(Debug, Clone)]
+pub struct BamOptions {
+    barcode_tag: Vec<u8>,
+    min_mapq: u8,
+    keep_duplicates: bool,
+    keep_secondary: bool,
+    reference: Option<PathBuf>,
+}
+
+impl BamOptions {
+    pub fn from_clap(sub_m: &ArgMatches) -> Result<BamOptions, Box<dyn Error>> {
+        Ok(BamOptions {
+            barcode_tag: sub_m
+                .value_of("barcode_tag")
+                .unwrap_or("CB")
+                .as_bytes()
+                .to_vec(),
+            min_mapq: sub_m.value_of("min_mapq").unwrap_or("30").parse::<u8>()?,
+            keep_duplicates: sub_m.is_present("keep_duplicates"),
+            keep_secondary: sub_m.is_present("keep_secondary"),
+            reference: sub_m.value_of("reference").map(PathBuf::from),
+        })
+    }
+
+    /// (start, end) of the fragment of a read fetched from `fetch_start`,
+    /// 0-based and end exclusive like the fragment files, `None` if filtered.
+    /// Paired reads yield one fragment per proper pair, spanning from the
+    /// leftmost mate to the end of its mate.
+    fn get_fragment(&self, read: &bam::Record, fetch_start: i64) -> Option<(u32, u32)> {
+        if read.is_unmapped() || read.is_quality_check_failed() || read.mapq() < self.min_mapq {
+            return None;
+        }
+        if !self.keep_duplicates && read.is_duplicate() {
+            return None;
+        }
+        if !self.keep_secondary && (read.is_secondary() || read.is_supplementary()) {
+            return None;
+        }
+
+        let (start, end) = match read.is_paired() {
+            true => {
+                if !read.is_proper_pair() || read.mtid() != read.tid() {
+                    return None;
+                }
+                get_pair_fragment(
+                    read.pos(),
+                    read.mpos(),
+                    read.insert_size().abs(),
+                    read.is_first_in_template(),
+                    fetch_start,
+                )?
+            }
+            false => (read.pos(), read.cigar().end_pos()),
+        };
+
+        Some((start as u32, end as u32))
+    }
+}
+
+/// (start, end) of the fragment of a proper pair out of one of its mates at
+/// `pos`, with its mate at `mpos`. The fragment is only given by the leftmost
+/// mate, or the first one of the template if both start at the same position,
+/// whatever the sign of the template length set by the aligner. The other mate
+/// gives it instead when the leftmost one starts before the fetched region.
+fn get_pair_fragment(
+    pos: i64,
+    mpos: i64,
+    length: i64,
+    is_first: bool,
+    fetch_start: i64,
+) -> Option<(i64, i64)> {
+    if length == 0 {
+        return None;
+    }
+
+    if pos < mpos || (pos == mpos && is_first) {
+        Some((pos, pos + length))
+    } else if mpos < pos && mpos < fetch_start {
+        Some((mpos, mpos + length))
+    } else {
+        None
+    }
+}
+
+/// fragments used for building the observations, and their weight.
+#[derive(Debug, Clone)]
+pub struct FragmentFilter {
+    min_length: u32,
+    max_length: u32,
+    count_duplicates: bool,
+}
+
+impl FragmentFilter {
+    pub fn from_clap(sub_m: &ArgMatches) -> Result<FragmentFilter, Box<dyn Error>> {
+        let max_length = match sub_m.value_of("max_fragment_length") {
+            Some(val) => val.parse::<u32>()?,
+            None => u32::MAX,
+        };
+
+        Ok(FragmentFilter {
+            min_length: sub_m
+                .value_of("min_fragment_length")
+                .unwrap_or("0")
+                .parse::<u32>()?,
+            max_length,
+            count_duplicates: sub_m.is_present("count_duplicates"),
+        })
+    }
+
+    pub fn count_duplicates(&self) -> bool {
+        self.count_duplicates
+    }
+
+    /// weight of a fragment from `start` to `end` (0-based, end exclusive)
+    /// with `count` duplicates, `None` if filtered.
+    fn get_weight(&self, start: u32, end: u32, count: u32) -> Option<ProbT> {
+        let length = end.saturating_sub(start);
+        if length < self.min_length || length > self.max_length {
+            return None;
+        }
+
+        match self.count_duplicates {
+            true => Some(count as ProbT),
+            false => Some(1.0),
+        }
+    }
+}
+
+/// depth normalisations of the anchor weighted signal, applied before the
+/// binarisation of the observations.
+#[derive(Debug, Clone, Default)]
+pub struct DepthNorm {
+    /// every fragment file of the assay.
+    library: bool,
+    /// the query cells sharing a barcode suffix, e.g. `-1`.
+    suffix: bool,
+    query_cell: bool,
+    reference_cell: bool,
+}
+
+impl DepthNorm {
+    pub fn from_clap(sub_m: &ArgMatches) -> DepthNorm {
+        let modes: Vec<&str> = sub_m
+            .values_of("depth_norm")
+            .map(|x| x.collect())
+            .unwrap_or_default();
+
+        DepthNorm {
+            library: modes.contains(&"library") || sub_m.is_present("normalize_libraries"),
+            suffix: modes.contains(&"suffix"),
+            query_cell: modes.contains(&"query_cell"),
+            reference_cell: modes.contains(&"reference_cell"),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.library || self.suffix || self.query_cell || self.reference_cell
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        let modes = [
+            (self.library, "library"),
+            (self.suffix, "suffix"),
+            (self.query_cell, "query_cell"),
+            (self.reference_cell, "reference_cell"),
+        ];
+
+        modes
+            .iter()
+            .filter(|x| x.0)
+            .map(|x| x.1.to_string())
+            .collect()
+    }
+}
+
+/// trailing `-<suffix>` of a barcode, empty if it has none.
+fn get_barcode_suffix(barcode: &str) -> &str {
+    match barcode.rfind('-') {
+        Some(pos) => &barcode[pos..],
+        None => "",
+    }
+}
+
+/// factors scaling every value to the mean of the non zero values,
+/// 1 for the zeros.
+fn get_mean_scale(values: &[ProbT]) -> Vec<ProbT> {
+    let non_zero: Vec<ProbT> = values.iter().filter(|&&x| x > 0.0).copied().collect();
+    if non_zero.is_empty() {
+        return vec![1.0; values.len()];
+    }
+
+    let mean = non_zero.iter().sum::<ProbT>() / non_zero.len() as ProbT;
+    values
+        .iter()
+        .map(|&x| match x > 0.0 {
+            true => mean / x,
+            false => 1.0,
+        })
+        .collect()
+}
+
+enum FragmentReader {
+    Tabix(tbx::Reader),
+    Bam(bam::IndexedReader, BamOptions),
+    Matrix(CountMatrix),
+}
+
+/// one fragment file (or BAM/CRAM, count matrix) of an assay.
+struct Library {
+    filepath: PathBuf,
+    reader: FragmentReader,
+    // replaces the common barcode suffix for the barcodes of the library.
+    barcode_suffix: Option<String>,
+    // library size normalisation factor of the fragment weights.
+    scale: ProbT,
+}
+
+impl Library {
+    /// opens a tabix indexed fragment file, an indexed BAM/CRAM file
+    /// based on the extension, or a directory with a bin by cell count matrix.
+    fn from_pathbuf(
+        filepath: PathBuf,
+        barcode_suffix: Option<String>,
+        bam_options: &BamOptions,
+    ) -> Library {
+        let reader = if filepath.is_dir() {
+            let matrix = CountMatrix::from_dir(&filepath)
+                .unwrap_or_else(|e| panic!("Could not read count matrix {:?}: {}", filepath, e));
+
+            FragmentReader::Matrix(matrix)
+        } else {
+            match filepath.extension().and_then(|x| x.to_str()) {
+                Some("bam") | Some("cram") => {
+                    let mut bam_reader = bam::IndexedReader::from_path(&filepath)
+                        .unwrap_or_else(|_| panic!("Could not open {:?}", filepath));
+                    if let Some(reference) = &bam_options.reference {
+                        bam_reader
+                            .set_reference(reference)
+                            .unwrap_or_else(|_| panic!("Could not set reference {:?}", reference));
+                    }
+
+                    FragmentReader::Bam(bam_reader, bam_options.clone())
+                }
+                _ => {
+                    let tbx_reader = tbx::Reader::from_path(&filepath)
+                        .unwrap_or_else(|_| panic!("Could not open {:?}", filepath));
+
+                    FragmentReader::Tabix(tbx_reader)
+                }
+            }
+        };
+
+        Library {
+            filepath,
+            reader,
+            barcode_suffix,
+            scale: 1.0,
+        }
+    }
+
+    fn tid(&self, seqname: &str) -> u64 {
+        match &self.reader {
+            FragmentReader::Tabix(reader) => match reader.tid(seqname) {
+                Ok(tid) => tid,
+                Err(_) => panic!("Could not resolve to contig ID"),
+            },
+            FragmentReader::Bam(reader, _) => match reader.header().tid(seqname.as_bytes()) {
+                Some(tid) => tid as u64,
+                None => panic!("Could not resolve to contig ID"),
+            },
+            FragmentReader::Matrix(matrix) => match matrix.tid(seqname) {
+                Some(tid) => tid,
+                None => panic!("Could not resolve to contig ID"),
+            },
+        }
+    }
+
+    /// anchored fragments of the region along with their weight.
+    fn fetch_records(
+        &mut self,
+        seqname: &str,
+        region: &Range<u32>,
+        barcodes: &BarcodeTable,
+        filter: &FragmentFilter,
+    ) -> Result<Vec<(Record<u32>, ProbT)>, Box<dyn Error>> {
+        let tid = self.tid(seqname);
+        let (suffix, filepath) = (self.barcode_suffix.as_deref(), &self.filepath);
+        match &mut self.reader {
+            FragmentReader::Tabix(reader) => {
+                // Set region to fetch.
+                reader
+                    .fetch(tid, region.start as u64, region.end as u64)
+                    .expect("Could not seek to fetch region");
+
+                let mut records = Vec::new();
+                for line in reader.records() {
+                    let line = String::from_utf8(line.unwrap()).expect("UTF8 conversion error");
+                    let (start, end, barcode, count) =
+                        parse_fragment(&line, filter.count_duplicates())
+                            .map_err(|e| format!("{} in {:?}, line: {}", e, filepath, line))?;
+
+                    let weight = match filter.get_weight(start, end, count) {
+                        Some(weight) => weight,
+                        None => continue,
+                    };
+                    if let Some(record) =
+                        Record::from_fragment(start, end, barcode, barcodes, suffix)
+                    {
+                        records.push((record, weight));
+                    }
+                }
+
+                Ok(records)
+            }
+            FragmentReader::Bam(reader, bam_options) => {
+                // Set region to fetch, padded for the leftmost mates of the
+                // pairs overlapping it.
+                let fetch_start = region.start.saturating_sub(BAM_FETCH_PADDING) as i64;
+                reader
+                    .fetch((tid as i32, fetch_start, region.end as i64))
+                    .map_err(|e| format!("Could not fetch {} in {:?}: {}", seqname, filepath, e))?;
+
+                let mut records = Vec::new();
+                for read in reader.records() {
+                    let read = read.map_err(|e| {
+                        format!("Could not read alignment in {:?}: {}", filepath, e)
+                    })?;
+                    let (start, end) = match bam_options.get_fragment(&read, fetch_start) {
+                        // clipped to the fragments overlapping the region.
+                        Some((start, end)) if end > region.start => (start, end),
+                        _ => continue,
+                    };
+
+                    let barcode = match read.aux(&bam_options.barcode_tag) {
+                        Some(Aux::String(barcode)) => std::str::from_utf8(barcode)
+                            .map_err(|e| format!("Invalid barcode in {:?}: {}", filepath, e))?,
+                        _ => continue,
+                    };
+
+                    let weight = match filter.get_weight(start, end, 1) {
+                        Some(weight) => weight,
+                        None => continue,
+                    };
+                    if let Some(record) =
+                        Record::from_fragment(start, end, barcode, barcodes, suffix)
+                    {
+                        records.push((record, weight));
+                    }
+                }
+
+                Ok(records)
+            }
+            FragmentReader::Matrix(matrix) => Ok(matrix.fetch(tid, region, barcodes, suffix)),
+        }
+    }
+}
+
+/// (path, barcode suffix) of the comma separated libraries of an assay, each
+/// given as `<path>` or `<path>:<barcode suffix>`. Paths with a comma or a
+/// colon can't be told apart from the separators and are rejected.
+fn parse_spec(spec: &str) -> Result<Vec<(PathBuf, Option<String>)>, String> {
+    if spec.contains(',') && PathBuf::from(spec).exists() {
+        return Err(format!("{}: paths with a comma are not supported", spec));
+    }
+
+    let mut libraries = Vec::new();
+    for library in spec.split(',') {
+        if library.is_empty() {
+            return Err(format!(
+                "{}: empty library in the comma separated list",
+                spec
+            ));
+        }
+        if library.contains(':') && PathBuf::from(library).exists() {
+            return Err(format!("{}: paths with a colon are not supported", library));
+        }
+
+        let (filepath, barcode_suffix) = match library.find(':') {
+            Some(pos) => (&library[..pos], Some(&library[pos + 1..])),
+            None => (library, None),
+        };
+        if let Some(suffix) = barcode_suffix {
+            if suffix.is_empty() || suffix.contains(':') || suffix.contains('/') {
+                return Err(format!("{}: invalid barcode suffix {:?}", library, suffix));
+            }
+        }
+        if filepath.is_empty() {
+            return Err(format!("{}: empty path", library));
+        }
+
+        libraries.push((
+            PathBuf::from(filepath),
+            barcode_suffix.map(|x| x.to_string()),
+        ));
+    }
+
+    Ok(libraries)
+}
+
+////////////////////////////////////////////
+/// Fragment
+/// the libraries (replicates) of an assay, merged on the fly.
+////////////////////////////////////////////
 pub struct Fragment {
-    _filepath: PathBuf,
-    reader: tbx::Reader,
+    libraries: Vec<Library>,
+    filter: FragmentFilter,
+    // depth normalisation factors of the query cells by barcode id and of
+    // the reference cells by common cell id, empty if not normalised.
+    query_scale: Vec<ProbT>,
+    reference_scale: Vec<ProbT>,
+    // depth normalisation factors by barcode suffix, folded into the
+    // query cell ones.
+    suffix_scale: Vec<(String, ProbT)>,
 }
 
 impl Fragment {
-    pub fn from_pathbuf(filepath: PathBuf) -> Fragment {
-        let tbx_reader = tbx::Reader::from_path(&filepath)
-            .unwrap_or_else(|_| panic!("Could not open {:?}", filepath));
+    /// opens the comma separated libraries of an assay, each given as
+    /// `<path>` or `<path>:<barcode suffix>`.
+    pub fn from_spec(
+        spec: &str,
+        bam_options: &BamOptions,
+        filter: &FragmentFilter,
+    ) -> Result<Fragment, Box<dyn Error>> {
+        let mut libraries = Vec::new();
+        for (filepath, barcode_suffix) in parse_spec(spec)? {
+            if !filepath.exists() {
+                return Err(format!("Could not find fragment file {:?}", filepath).into());
+            }
 
-        Fragment {
-            _filepath: filepath,
-            reader: tbx_reader,
+            libraries.push(Library::from_pathbuf(filepath, barcode_suffix, bam_options));
         }
+
+        Ok(Fragment {
+            libraries,
+            filter: filter.clone(),
+            query_scale: Vec::new(),
+            reference_scale: Vec::new(),
+            suffix_scale: Vec::new(),
+        })
+    }
+
+    pub fn filepaths(&self) -> Vec<PathBuf> {
+        self.libraries.iter().map(|x| x.filepath.clone()).collect()
     }
 
-    pub fn _filepath(&self) -> &str {
-        self._filepath.to_str().unwrap()
+    pub fn is_matrix(&self) -> bool {
+        self.libraries
+            .iter()
+            .any(|x| matches!(x.reader, FragmentReader::Matrix(_)))
     }
 
-    pub fn tid(&self, seqname: &str) -> u64 {
-        match self.reader.tid(seqname) {
-            Ok(tid) => tid,
-            Err(_) => panic!("Could not resolve to contig ID"),
+    /// computes the depth normalisation factors out of the anchored
+    /// fragments of every query cell over the regions. Libraries, barcode
+    /// suffixes and query cells are scaled to the mean of their total
+    /// fragments, in that order, reference
+    /// cells to the mean of their total anchor weighted signal.
+    pub fn normalize_depth(
+        &mut self,
+        regions: &[(String, Range<u32>)],
+        assay_cells: &HashMap<u32, HashMap<u32, ProbT>>,
+        barcodes: &BarcodeTable,
+        num_common_cells: usize,
+        depth_norm: &DepthNorm,
+    ) -> Result<(), Box<dyn Error>> {
+        let filter = &self.filter;
+        let num_barcodes = barcodes.num_barcodes();
+        let mut library_depths: Vec<Vec<ProbT>> = Vec::new();
+        for library in self.libraries.iter_mut() {
+            let mut depth = vec![0.0; num_barcodes];
+            for (seqname, region) in regions {
+                library
+                    .fetch_records(seqname, region, barcodes, filter)?
+                    .into_iter()
+                    .for_each(|(record, weight)| depth[record.id() as usize] += weight);
+            }
+            library_depths.push(depth);
+        }
+
+        if depth_norm.library {
+            let sizes: Vec<ProbT> = library_depths.iter().map(|x| x.iter().sum()).collect();
+            self.libraries
+                .iter_mut()
+                .zip(get_mean_scale(&sizes))
+                .for_each(|(library, scale)| library.scale = scale);
+        }
+
+        let mut depths = vec![0.0; num_barcodes];
+        for (library, library_depth) in self.libraries.iter().zip(library_depths) {
+            depths
+                .iter_mut()
+                .zip(library_depth)
+                .for_each(|(x, depth)| *x += depth * library.scale);
+        }
+
+        let mut query_scale = vec![1.0; num_barcodes];
+        if depth_norm.suffix {
+            let suffixes: Vec<&str> = (0..num_barcodes)
+                .map(|id| get_barcode_suffix(barcodes.name(id as u32)))
+                .collect();
+            let mut names = suffixes.clone();
+            names.sort_unstable();
+            names.dedup();
+
+            let mut sizes = vec![0.0; names.len()];
+            for (suffix, depth) in suffixes.iter().zip(depths.iter()) {
+                sizes[names.binary_search(suffix).unwrap()] += depth;
+            }
+            let scales = get_mean_scale(&sizes);
+            for (id, suffix) in suffixes.iter().enumerate() {
+                query_scale[id] = scales[names.binary_search(suffix).unwrap()];
+                depths[id] *= query_scale[id];
+            }
+            self.suffix_scale = names.into_iter().map(String::from).zip(scales).collect();
+        }
+
+        if depth_norm.query_cell {
+            let cell_scales = get_mean_scale(&depths);
+            for ((x, scale), cell_scale) in depths
+                .iter_mut()
+                .zip(query_scale.iter_mut())
+                .zip(cell_scales)
+            {
+                *scale *= cell_scale;
+                *x *= cell_scale;
+            }
         }
+        if depth_norm.suffix || depth_norm.query_cell {
+            self.query_scale = query_scale;
+        }
+
+        if depth_norm.reference_cell {
+            let mut signal = vec![0.0; num_common_cells];
+            for (&cb, dict) in assay_cells {
+                for (&cell_id, &prob) in dict {
+                    signal[cell_id as usize] += depths[cb as usize] * prob;
+                }
+            }
+            self.reference_scale = get_mean_scale(&signal);
+        }
+
+        Ok(())
+    }
+
+    /// writes the depth normalisation factors of the assay as
+    /// `assay, level, name, factor` rows.
+    pub fn write_depth_factors(
+        &self,
+        writer: &mut dyn Write,
+        assay: usize,
+        barcodes: &BarcodeTable,
+        common_cells: &[String],
+    ) -> Result<(), Box<dyn Error>> {
+        for library in &self.libraries {
+            let name = library.filepath.display();
+            writeln!(writer, "{}\tlibrary\t{}\t{}", assay, name, library.scale)?;
+        }
+        for (suffix, scale) in &self.suffix_scale {
+            writeln!(writer, "{}\tsuffix\t{}\t{}", assay, suffix, scale)?;
+        }
+        for (id, scale) in self.query_scale.iter().enumerate() {
+            let name = barcodes.name(id as u32);
+            writeln!(writer, "{}\tquery_cell\t{}\t{}", assay, name, scale)?;
+        }
+        for (cell_id, scale) in self.reference_scale.iter().enumerate() {
+            let name = &common_cells[cell_id];
+            writeln!(writer, "{}\treference_cell\t{}\t{}", assay, name, scale)?;
+        }
+
+        Ok(())
+    }
+
+    /// anchored fragments of the region of all the libraries, with their
+    /// weights scaled by the library size factors.
+    fn fetch_records(
+        &mut self,
+        seqname: &str,
+        region: &Range<u32>,
+        barcodes: &BarcodeTable,
+    ) -> Result<Vec<(Record<u32>, ProbT)>, Box<dyn Error>> {
+        let filter = &self.filter;
+        let mut records = Vec::new();
+        for library in self.libraries.iter_mut() {
+            let scale = library.scale;
+            records.extend(
+                library
+                    .fetch_records(seqname, region, barcodes, filter)?
+                    .into_iter()
+                    .map(|(record, weight)| (record, weight * scale)),
+            );
+        }
+
+        Ok(records)
     }
 
+    /// records of a chromosome region indexed by query cell, the records
+    /// of the reference cells are built from them when needed.
     pub fn fetch(
         &mut self,
-        tid: u64,
+        seqname: &str,
         region: &Range<u32>,
-        assay_cells: &HashMap<u64, HashMap<u32, ProbT>>,
+        assay_cells: &HashMap<u32, HashMap<u32, ProbT>>,
+        barcodes: &BarcodeTable,
         num_common_cells: usize,
-    ) -> Vec<CellRecords<ProbT>> {
-        // Set region to fetch.
-        self.reader
-            .fetch(tid, region.start as u64, region.end as u64)
-            .expect("Could not seek to fetch region");
-
-        let all_records: Vec<Record<u64>> = self
-            .reader
-            .records()
-            .map(|x| String::from_utf8(x.unwrap()).expect("UTF8 conversion error"))
-            .map(|x| Record::from_string(x, assay_cells))
-            .flatten()
-            .collect();
-
-        let mut norm: HashMap<u32, u32> = HashMap::new();
-        for (_, val) in assay_cells {
-            for (cb, _) in val {
-                let ct = norm.entry(*cb).or_insert(0);
-                *ct += 1;
+    ) -> Result<AssayRecords<ProbT>, Box<dyn Error>> {
+        let all_records = self.fetch_records(seqname, region, barcodes)?;
+
+        let mut query_records: Vec<Vec<(u32, Record<ProbT>)>> =
+            vec![Vec::new(); barcodes.num_barcodes()];
+        for (index, (record, weight)) in all_records.into_iter().enumerate() {
+            let cb = record.id();
+            if !assay_cells.contains_key(&cb) {
+                continue;
             }
+
+            let weight = weight * self.query_scale.get(cb as usize).unwrap_or(&1.0);
+            let new_record = Record::new_with_id(record.range(), weight);
+            query_records[cb as usize].push((index as u32, new_record));
         }
 
-        let mut cell_records: Vec<Vec<Record<ProbT>>> = vec![Vec::new(); num_common_cells];
-        for record in all_records {
-            let cb = record.id();
-            if let Some(dict) = assay_cells.get(&cb) {
-                for (&cell_id, &prob) in dict {
-                    let nprob = prob / *norm.get(&cell_id).unwrap() as ProbT;
-                    let new_record = Record::new_with_id(&record.range(), nprob);
-                    cell_records[cell_id as usize].push(new_record);
-                }
-            };
+        let mut anchors: Vec<Vec<(u32, ProbT)>> = vec![Vec::new(); num_common_cells];
+        for (&cb, dict) in assay_cells {
+            for (&cell_id, &prob) in dict {
+                anchors[cell_id as usize].push((cb, prob));
+            }
         }
 
-        let cell_records: Vec<CellRecords<ProbT>> =
-            cell_records.into_iter().map(CellRecords::new).collect();
+        Ok(AssayRecords::new(
+            query_records,
+            anchors,
+            self.reference_scale.clone(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_spec() {
+        let library =
+            |path: &str, suffix: Option<&str>| (PathBuf::from(path), suffix.map(|x| x.to_string()));
+        assert_eq!(parse_spec("a.tsv.gz"), Ok(vec![library("a.tsv.gz", None)]));
+        assert_eq!(
+            parse_spec("a.tsv.gz:-1,b.tsv.gz:-2,c.bam"),
+            Ok(vec![
+                library("a.tsv.gz", Some("-1")),
+                library("b.tsv.gz", Some("-2")),
+                library("c.bam", None),
+            ])
+        );
+
+        for spec in &["", "a,", ",a", "a:", ":-1", "a:b:-1", "a:-1/b"] {
+            assert!(parse_spec(spec).is_err(), "{}", spec);
+        }
+
+        // existing files which would be split on the separators.
+        let dir = std::env::temp_dir().join("schrom_test_fragment_spec");
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in &["x,y", "x:y"] {
+            let path = dir.join(name);
+            std::fs::write(&path, "").unwrap();
+            assert!(parse_spec(path.to_str().unwrap()).is_err(), "{}", name);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_pair_fragment() {
+        // only the leftmost mate gives the fragment, whatever the sign of the
+        // template length.
+        assert_eq!(get_pair_fragment(100, 250, 300, false, 0), Some((100, 400)));
+        assert_eq!(get_pair_fragment(250, 100, 300, true, 0), None);
+
+        // mates starting at the same position: only the first of the template.
+        assert_eq!(get_pair_fragment(100, 100, 50, true, 0), Some((100, 150)));
+        assert_eq!(get_pair_fragment(100, 100, 50, false, 0), None);
+
+        // leftmost mate before the fetched region.
+        assert_eq!(
+            get_pair_fragment(250, 100, 300, false, 200),
+            Some((100, 400))
+        );
+        assert_eq!(get_pair_fragment(250, 100, 300, false, 100), None);
+        assert_eq!(get_pair_fragment(100, 100, 50, false, 200), None);
 
-        cell_records
+        assert_eq!(get_pair_fragment(100, 250, 0, false, 0), None);
     }
 }
@@ -1,10 +1,18 @@
+use crate::anchor::{self, AnchorWeighting};
+use crate::barcode::{BarcodeOptions, BarcodeTable};
+use crate::blacklist::Blacklist;
 use crate::config::ProbT;
-use crate::config::{CHR_LENS, CHR_LENS_SMALL, WINDOW_SIZE};
-use crate::fragment::Fragment;
-use crate::model;
-use crate::quantify;
+use crate::config::{CHR_LENS, CHR_LENS_SMALL, IMPUTE_BATCH_CELLS, WINDOW_SIZE};
+use crate::evaluate::{self, ScoreHistogram, EVALUATION_FILE};
+use crate::file::write_binary;
+use crate::fragment::{BamOptions, DepthNorm, Fragment, FragmentFilter, DEPTH_FACTORS_FILE};
+use crate::manifest::{Manifest, RunRecord};
+use crate::model::{self, Hmm};
+use crate::posterior::{self, CellPosterior};
+use crate::quantify::{
+    self, ObservationMode, ObservationOptions, PosteriorOptions, PosteriorOutput, ThresholdMode,
+};
 use crate::record::{AssayRecords, Experiment};
-use bio::data_structures::interval_tree::IntervalTree;
 
 use clap::ArgMatches;
 use crossbeam::queue::ArrayQueue;
@@ -16,9 +24,10 @@ use std::error::Error;
 use std::io::BufRead;
 use std::io::Write;
 use std::ops::Range;
+use std::time::Instant;
 
-//use flate2::write::GzEncoder;
-//use flate2::Compression;
+use flate2::write::GzEncoder;
+use flate2::Compression;
 
 pub fn get_cells(sub_m: &ArgMatches) -> Result<Vec<String>, Box<dyn Error>> {
     // reading in cell names of common assay.
@@ -33,10 +42,195 @@ pub fn get_cells(sub_m: &ArgMatches) -> Result<Vec<String>, Box<dyn Error>> {
     Ok(common_cells)
 }
 
+/// indices of the common cells processed by this run, either a
+/// contiguous `--shard i/n` slice or the cells listed in `--cells_subset`.
+fn get_shard_cells(
+    sub_m: &ArgMatches,
+    common_cells: &[String],
+) -> Result<(Vec<usize>, Option<(usize, usize)>), Box<dyn Error>> {
+    let num_common_cells = common_cells.len();
+
+    if let Some(shard) = sub_m.value_of("shard") {
+        let toks: Vec<&str> = shard.split('/').collect();
+        let (index, num_shards) = match toks.as_slice() {
+            [index, num_shards] => (index.parse::<usize>()?, num_shards.parse::<usize>()?),
+            _ => return Err(format!("can't parse shard {}, expected i/n", shard).into()),
+        };
+        if index == 0 || index > num_shards {
+            return Err(format!(
+                "shard index should be in [1, {}], found {}",
+                num_shards, index
+            )
+            .into());
+        }
+
+        let start = (index - 1) * num_common_cells / num_shards;
+        let end = index * num_common_cells / num_shards;
+        return Ok(((start..end).collect(), Some((index, num_shards))));
+    }
+
+    if sub_m.is_present("cells_subset") {
+        let string_index_common_cells: HashMap<&String, usize> = common_cells
+            .iter()
+            .enumerate()
+            .map(|(i, x)| (x, i))
+            .collect();
+
+        let subset_cells =
+            posterior::read_cells(carina::file::file_path_from_clap(sub_m, "cells_subset")?)?;
+        let mut cell_ids = Vec::with_capacity(subset_cells.len());
+        for cell in subset_cells {
+            match string_index_common_cells.get(&cell) {
+                Some(&cell_id) => cell_ids.push(cell_id),
+                None => {
+                    return Err(format!("can't find cell {} in the common cell list", cell).into())
+                }
+            }
+        }
+        cell_ids.sort_unstable();
+        cell_ids.dedup();
+
+        return Ok((cell_ids, None));
+    }
+
+    Ok(((0..num_common_cells).collect(), None))
+}
+
+/// records the cells and the shard processed by this run, required for
+/// running `transform` and `merge` on the output.
+fn write_shard_info(
+    out_path: &std::path::Path,
+    common_cells: &[String],
+    cell_ids: &[usize],
+    shard: Option<(usize, usize)>,
+) -> Result<(), Box<dyn Error>> {
+    std::fs::create_dir_all(out_path)?;
+
+    let mut cells_file =
+        std::io::BufWriter::new(std::fs::File::create(out_path.join("cells.txt"))?);
+    for &cell_id in cell_ids {
+        writeln!(cells_file, "{}", common_cells[cell_id])?;
+    }
+
+    if let Some((index, num_shards)) = shard {
+        let mut shard_file = std::fs::File::create(out_path.join(posterior::SHARD_FILE))?;
+        writeln!(shard_file, "{}/{}", index, num_shards)?;
+    }
+
+    Ok(())
+}
+
+/// (reference cell, query barcode, score) of an anchor line, optionally
+/// preceded by a row name column as written by R.
+fn parse_anchor(line: &str, delimiter: Option<char>) -> Result<(&str, &str, ProbT), String> {
+    let toks = anchor::split_fields(line, delimiter);
+
+    let toks = match toks.len() {
+        3 => &toks[..],
+        4 => &toks[1..],
+        num_toks => return Err(format!("expected 3 columns, found {}", num_toks)),
+    };
+    if toks[0].is_empty() || toks[1].is_empty() {
+        return Err("empty cell name".to_string());
+    }
+    let score = toks[2]
+        .parse::<ProbT>()
+        .map_err(|_| format!("can't parse score {}", toks[2]))?;
+    if !score.is_finite() || score < 0.0 {
+        return Err(format!("invalid score {}", score));
+    }
+
+    Ok((toks[0], toks[1], score))
+}
+
+/// whether the last column of an anchor line is a number.
+fn is_score(line: &str, delimiter: Option<char>) -> bool {
+    anchor::split_fields(line, delimiter)
+        .last()
+        .map_or(false, |x| x.parse::<ProbT>().is_ok())
+}
+
+/// (reference cell index, query barcode, score) of the anchors of a file,
+/// with the reference cells resolved in the common cells.
+fn read_anchor_file(
+    file_path: &std::path::Path,
+    string_index_common_cells: &HashMap<String, u32>,
+    skip_unknown_cells: bool,
+) -> Result<Vec<(u32, String, ProbT)>, Box<dyn Error>> {
+    let mut anchors = Vec::new();
+    let mut delimiter = None;
+    let mut num_unknown_cells = 0;
+    let (mut num_records, mut header) = (0, None);
+    let reader = carina::file::bufreader_from_filepath(file_path.to_path_buf())?;
+    for (line_num, line) in reader.lines().enumerate() {
+        let record = line?;
+        if record.trim().is_empty() || record.starts_with('#') {
+            continue;
+        }
+        num_records += 1;
+        if num_records == 1 {
+            delimiter = anchor::get_delimiter(&record);
+        }
+
+        let (cell, barcode, score) = match parse_anchor(&record, delimiter) {
+            Ok(anchor) => anchor,
+            // the first line can be a header, if its score column isn't a
+            // number and the next line parses.
+            Err(e) if num_records == 1 && !is_score(&record, delimiter) => {
+                header = Some((line_num, record, e));
+                continue;
+            }
+            Err(e) => return Err(format!("{:?} line {}: {}", file_path, line_num + 1, e).into()),
+        };
+        if let Some((header_num, header, _)) = header.take() {
+            info!(
+                "Skipping header of {:?} line {}: {}",
+                file_path,
+                header_num + 1,
+                header
+            );
+        }
+
+        let common_cell_index: u32 = match string_index_common_cells.get(cell) {
+            Some(&index) => index,
+            None if skip_unknown_cells => {
+                num_unknown_cells += 1;
+                continue;
+            }
+            None => {
+                return Err(format!(
+                    "{:?} line {}: can't find cell {} in the common cell list",
+                    file_path,
+                    line_num + 1,
+                    cell
+                )
+                .into())
+            }
+        };
+
+        anchors.push((common_cell_index, barcode.to_string(), score));
+    }
+
+    if num_unknown_cells > 0 {
+        warn!(
+            "Skipped {} anchors of {:?} with cells missing from the common cell list",
+            num_unknown_cells, file_path
+        );
+    }
+    if let Some((header_num, _, e)) = header {
+        return Err(format!("{:?} line {}: {}", file_path, header_num + 1, e).into());
+    }
+    if anchors.is_empty() {
+        return Err(format!("no anchors found in {:?}", file_path).into());
+    }
+
+    Ok(anchors)
+}
+
 fn get_anchors(
     sub_m: &ArgMatches,
     common_cells: &[String],
-) -> Result<Vec<HashMap<u64, HashMap<u32, ProbT>>>, Box<dyn Error>> {
+) -> Result<(Vec<BarcodeTable>, Vec<HashMap<u32, HashMap<u32, ProbT>>>), Box<dyn Error>> {
     // reading anchors
     let string_index_common_cells: HashMap<String, u32> = common_cells
         .iter()
@@ -44,45 +238,158 @@ fn get_anchors(
         .map(|(i, x)| (x.clone(), i as u32))
         .collect();
 
+    let skip_unknown_cells = sub_m.is_present("skip_unknown_cells");
+    let barcode_options = BarcodeOptions::from_clap(sub_m);
+    let mut vec_barcodes = Vec::with_capacity(5);
     let mut vec_anchor_triplets = Vec::with_capacity(5);
 
     let anchor_file_paths = carina::file::files_path_from_clap(sub_m, "anchors")?;
     for file_path in anchor_file_paths {
-        let mut anchor_triplets: HashMap<u64, HashMap<u32, ProbT>> = HashMap::with_capacity(10_000);
-
-        let reader = carina::file::bufreader_from_filepath(file_path)?;
-        for line in reader.lines() {
-            let record = line.unwrap();
-            let toks: Vec<&str> = record.split_whitespace().collect();
+        let mut barcodes = BarcodeTable::new(barcode_options.clone());
+        let mut anchor_triplets: HashMap<u32, HashMap<u32, ProbT>> = HashMap::with_capacity(10_000);
 
-            let common_cell_index: u32 = *string_index_common_cells
-                .get(toks[0])
-                .expect("can't find cell name in the common cell list");
-
-            let cb_str: Vec<&str> = toks[1].split('-').collect();
-            let cb_id = cb_str.get(1).unwrap().parse::<u8>().unwrap();
-
-            let assay_cell_index: u64 =
-                carina::barcode::cb_string_to_u64_with_id(cb_str[0].as_bytes(), 16, cb_id)?;
-
-            let score = toks[2].parse::<ProbT>().unwrap();
+        let anchors = read_anchor_file(&file_path, &string_index_common_cells, skip_unknown_cells)?;
+        for (common_cell_index, barcode, score) in anchors {
+            let assay_cell_index: u32 = barcodes.intern(&barcode);
             anchor_triplets
                 .entry(assay_cell_index)
                 .or_insert_with(HashMap::new)
                 .insert(common_cell_index, score);
         }
 
+        vec_barcodes.push(barcodes);
         vec_anchor_triplets.push(anchor_triplets);
     }
 
-    Ok(vec_anchor_triplets)
+    Ok((vec_barcodes, vec_anchor_triplets))
+}
+
+/// anchors of the same cell mode, where the fragments of all the assays
+/// share the barcodes of the common cells. Every cell is anchored to
+/// itself, and to its neighbours of the kNN graph if given.
+fn get_same_cell_anchors(
+    sub_m: &ArgMatches,
+    common_cells: &[String],
+    num_assays: usize,
+) -> Result<(Vec<BarcodeTable>, Vec<HashMap<u32, HashMap<u32, ProbT>>>), Box<dyn Error>> {
+    let string_index_common_cells: HashMap<String, u32> = common_cells
+        .iter()
+        .enumerate()
+        .map(|(i, x)| (x.clone(), i as u32))
+        .collect();
+
+    let self_weight = sub_m
+        .value_of("knn_self_weight")
+        .unwrap_or("1")
+        .parse::<ProbT>()?;
+    if !self_weight.is_finite() || self_weight < 0.0 {
+        return Err(format!("invalid knn_self_weight {}", self_weight).into());
+    }
+    let mut neighbours: Vec<(u32, u32, ProbT)> = common_cells
+        .iter()
+        .enumerate()
+        .map(|(i, _)| (i as u32, i as u32, self_weight))
+        .collect();
+
+    if let Some(knn_path) = sub_m.value_of("knn_graph") {
+        let knn_path = std::path::PathBuf::from(knn_path);
+        let skip_unknown_cells = sub_m.is_present("skip_unknown_cells");
+        let mut num_unknown_cells = 0;
+        let edges = read_anchor_file(&knn_path, &string_index_common_cells, skip_unknown_cells)?;
+        for (cell_index, neighbour, weight) in edges {
+            match string_index_common_cells.get(&neighbour) {
+                Some(&neighbour_index) => neighbours.push((cell_index, neighbour_index, weight)),
+                None if skip_unknown_cells => num_unknown_cells += 1,
+                None => {
+                    return Err(format!(
+                        "{:?}: can't find neighbour {} in the common cell list",
+                        knn_path, neighbour
+                    )
+                    .into())
+                }
+            }
+        }
+
+        if num_unknown_cells > 0 {
+            warn!(
+                "Skipped {} edges of {:?} with cells missing from the common cell list",
+                num_unknown_cells, knn_path
+            );
+        }
+        info!(
+            "Smoothing with {} kNN edges",
+            neighbours.len() - common_cells.len()
+        );
+    }
+
+    let barcode_options = BarcodeOptions::from_clap(sub_m);
+    let mut barcodes = BarcodeTable::new(barcode_options);
+    let mut anchor_triplets: HashMap<u32, HashMap<u32, ProbT>> = HashMap::new();
+    for (cell_index, neighbour_index, weight) in neighbours {
+        let assay_cell_index = barcodes.intern(&common_cells[neighbour_index as usize]);
+        anchor_triplets
+            .entry(assay_cell_index)
+            .or_insert_with(HashMap::new)
+            .insert(cell_index, weight);
+    }
+
+    let vec_barcodes = (0..num_assays).map(|_| barcodes.clone()).collect();
+    let vec_anchor_triplets = vec![anchor_triplets; num_assays];
+
+    Ok((vec_barcodes, vec_anchor_triplets))
+}
+
+/// what `run` writes for the reference cells.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RunMode {
+    /// the state posteriors of the hmm.
+    Posterior,
+    /// the anchor imputed signal of every assay.
+    Impute,
+    /// the mark probabilities predicted from the state posteriors.
+    Predict,
+    /// how well every mark is predicted with its own assay held out.
+    Evaluate,
 }
 
 pub fn callback(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    run(sub_m, RunMode::Posterior)
+}
+
+pub fn impute_callback(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    run(sub_m, RunMode::Impute)
+}
+
+pub fn predict_callback(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    run(sub_m, RunMode::Predict)
+}
+
+pub fn evaluate_callback(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    run(sub_m, RunMode::Evaluate)
+}
+
+/// fetches the anchor imputed signal of the reference cells per chromosome,
+/// and writes either the signal itself or what the hmm infers from it.
+fn run(sub_m: &ArgMatches, mode: RunMode) -> Result<(), Box<dyn Error>> {
     let num_threads: usize = sub_m.value_of("threads").unwrap().parse().unwrap();
 
-    let hmm = model::get_hmm_params(&sub_m)?;
-    info!("Read HMM model paramers: {:?}", hmm);
+    // required by every mode but impute, which only records it.
+    let hmm = match sub_m.is_present("model") {
+        true => {
+            let hmm = model::get_hmm_params(&sub_m)?;
+            info!("Read HMM model paramers: {:?}", hmm);
+            Some(hmm)
+        }
+        false => None,
+    };
+    if mode == RunMode::Evaluate {
+        if sub_m.is_present("resume") {
+            return Err("evaluate can't be resumed, the scores are written at the end".into());
+        }
+        if hmm.as_ref().unwrap().num_assays() < 2 {
+            return Err("evaluate needs at least two measured marks".into());
+        }
+    }
 
     let common_cells = get_cells(&sub_m)?;
     let num_common_cells = common_cells.len();
@@ -92,7 +399,31 @@ pub fn callback(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
         common_cells[0]
     );
 
-    let vec_anchor_triplets = get_anchors(&sub_m, &common_cells)?;
+    let (shard_cells, shard) = get_shard_cells(&sub_m, &common_cells)?;
+    let num_shard_cells = shard_cells.len();
+    if num_shard_cells != num_common_cells {
+        info!(
+            "Processing {} of the {} common cells, shard={:?}",
+            num_shard_cells, num_common_cells, shard
+        );
+    }
+
+    let (vec_barcodes, mut vec_anchor_triplets) = match sub_m.is_present("same_cell") {
+        true => {
+            let num_assays = sub_m.values_of("fragments").unwrap().len();
+            info!("Using the same cells for all {} assays", num_assays);
+            get_same_cell_anchors(&sub_m, &common_cells, num_assays)?
+        }
+        false => get_anchors(&sub_m, &common_cells)?,
+    };
+    let anchor_weighting = AnchorWeighting::from_clap(sub_m)?;
+    info!("Weighting anchors with {:?}", anchor_weighting);
+    for (i, anchor_triplets) in vec_anchor_triplets.iter_mut().enumerate() {
+        let num_dropped = anchor_weighting.apply(anchor_triplets);
+        if num_dropped > 0 {
+            info!("Dropped {} anchors of assay {}", num_dropped, i);
+        }
+    }
     let num_assays = vec_anchor_triplets.len();
     let assay_num_cells: Vec<usize> = vec_anchor_triplets.iter().map(|x| x.len()).collect();
     let assay_num_anchors: Vec<usize> = vec_anchor_triplets
@@ -104,14 +435,125 @@ pub fn callback(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
         num_assays, assay_num_cells, assay_num_anchors
     );
 
-    let fragment_file_paths = carina::file::files_path_from_clap(sub_m, "fragments")?;
-    let mut frags: Vec<Fragment> = fragment_file_paths
-        .into_iter()
-        .map(Fragment::from_pathbuf)
+    let bam_options = BamOptions::from_clap(sub_m)?;
+    let fragment_filter = FragmentFilter::from_clap(sub_m)?;
+    let mut frags: Vec<Fragment> = sub_m
+        .values_of("fragments")
+        .unwrap()
+        .map(|x| Fragment::from_spec(x, &bam_options, &fragment_filter))
+        .collect::<Result<_, _>>()?;
+    if frags.len() != num_assays {
+        return Err(format!(
+            "Found {} fragment inputs for {} anchor files",
+            frags.len(),
+            num_assays
+        )
+        .into());
+    }
+    if let Some(hmm) = &hmm {
+        if mode != RunMode::Impute && num_assays != hmm.num_assays() {
+            return Err(format!(
+                "Found {} assays for the {} measured marks of the model",
+                num_assays,
+                hmm.num_assays()
+            )
+            .into());
+        }
+    }
+    let fragment_file_paths: Vec<std::path::PathBuf> =
+        frags.iter().flat_map(|x| x.filepaths()).collect();
+
+    // the kNN graph takes the place of the anchors in the same cell mode.
+    let anchor_file_paths: Vec<std::path::PathBuf> = ["anchors", "knn_graph"]
+        .iter()
+        .filter_map(|x| sub_m.values_of(x))
+        .flatten()
+        .map(std::path::PathBuf::from)
         .collect();
+    let model_path = match hmm.is_some() {
+        true => Some(carina::file::file_path_from_clap(sub_m, "model")?),
+        false => None,
+    };
+    let mut manifest = Manifest::new(
+        hmm.as_ref().zip(model_path.as_deref()),
+        num_assays,
+        &fragment_file_paths,
+        &anchor_file_paths,
+        &carina::file::file_path_from_clap(sub_m, "common_cells")?,
+        shard,
+        sub_m.is_present("checksum"),
+    )?;
+    let depth_norm = DepthNorm::from_clap(sub_m);
+    manifest.depth_norm = depth_norm.names();
+    manifest.anchor_weighting = anchor_weighting;
+    let observation_options = ObservationOptions::from_clap(sub_m)?;
+    if let ThresholdMode::Poisson(pvalue) = observation_options.thresholds {
+        manifest.threshold_pvalue = Some(pvalue);
+    }
+
+    match mode {
+        RunMode::Posterior => (),
+        RunMode::Impute => manifest.format = "imputed".to_string(),
+        RunMode::Predict => {
+            // the marks take the place of the states in the "long" format.
+            let hmm = hmm.as_ref().unwrap();
+            manifest.marks = hmm.mark_names().to_vec();
+            manifest.states = (1..=hmm.num_marks()).collect();
+        }
+        RunMode::Evaluate => manifest.format = "evaluation".to_string(),
+    }
+
+    // nothing is written to the output directory before the inputs of a
+    // resumed run are validated against the earlier ones.
+    let out_root = std::path::PathBuf::from(sub_m.value_of("output").unwrap());
+    if sub_m.is_present("resume") {
+        if let Some(old_manifest) = Manifest::from_dir(&out_root)? {
+            old_manifest.check_inputs(&manifest)?;
+            manifest.runs = old_manifest.runs;
+        }
+    }
+    write_shard_info(&out_root, &common_cells, &shard_cells, shard)?;
+    manifest.runs.push(RunRecord::new(match mode {
+        RunMode::Posterior => "hmm",
+        RunMode::Impute => "impute",
+        RunMode::Predict => "predict",
+        RunMode::Evaluate => "evaluate",
+    }));
+    manifest.write(&out_root)?;
+
+    info!(
+        "Counting fragments with {:?} mode, {:?} thresholds",
+        observation_options.mode, observation_options.thresholds
+    );
+    if frags.iter().any(|x| x.is_matrix()) {
+        if let ObservationMode::CutSites(..) = observation_options.mode {
+            return Err(
+                "count matrices don't have cut sites, use overlap or midpoint count mode".into(),
+            );
+        }
+    }
+
+    let checkpoint = sub_m.is_present("checkpoint");
+    if checkpoint {
+        info!("Checkpointing the forward probabilities to save memory");
+    }
+
+    let blacklist = match sub_m.value_of("blacklist") {
+        Some(_) => {
+            let blacklist =
+                Blacklist::from_path(carina::file::file_path_from_clap(sub_m, "blacklist")?)?;
+            info!("Found {} blacklisted regions", blacklist.num_regions());
+            blacklist
+        }
+        None => Blacklist::default(),
+    };
 
     let num_chrs = CHR_LENS.len();
     let onlyone = sub_m.is_present("onlyone");
+    let resume = sub_m.is_present("resume");
+    if resume {
+        info!("Resuming, skipping completed chromosomes and cells");
+    }
 
     let (chrs, chr_lens) = match onlyone {
         true => ((0..1), CHR_LENS_SMALL),
@@ -119,11 +561,93 @@ pub fn callback(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
     };
     info!("Found total {} chromosomes", chrs.len());
 
+    if depth_norm.is_enabled() {
+        info!(
+            "Computing depth normalisation factors: {:?}",
+            depth_norm.names()
+        );
+        let regions: Vec<(String, Range<u32>)> = chrs
+            .clone()
+            .map(|chr_id| (format!("chr{}", chr_id + 1), 0..chr_lens[chr_id]))
+            .collect();
+
+        let mut file = std::fs::File::create(out_root.join(DEPTH_FACTORS_FILE))?;
+        for (i, frag) in frags.iter_mut().enumerate() {
+            frag.normalize_depth(
+                &regions,
+                &vec_anchor_triplets[i],
+                &vec_barcodes[i],
+                num_common_cells,
+                &depth_norm,
+            )?;
+            frag.write_depth_factors(&mut file, i, &vec_barcodes[i], &common_cells)?;
+        }
+    }
+
+    if num_shard_cells != num_common_cells {
+        // only keep the anchors of the reference cells in the shard, once
+        // the depth factors are computed over all the cells so that they
+        // are the same in every shard.
+        let mut is_shard_cell = vec![false; num_common_cells];
+        shard_cells.iter().for_each(|&x| is_shard_cell[x] = true);
+        vec_anchor_triplets.iter_mut().for_each(|anchor_triplets| {
+            anchor_triplets
+                .values_mut()
+                .for_each(|x| x.retain(|&cell_id, _| is_shard_cell[cell_id as usize]));
+            anchor_triplets.retain(|_, x| !x.is_empty());
+        });
+    }
+
+    // the models with each measured mark held out in turn.
+    let holdout_hmms: Vec<Hmm> = match mode {
+        RunMode::Evaluate => {
+            let hmm = hmm.as_ref().unwrap();
+            (0..hmm.num_assays())
+                .map(|x| hmm.without_assay(x))
+                .collect()
+        }
+        _ => Vec::new(),
+    };
+    let mut cell_histograms: HashMap<usize, Vec<ScoreHistogram>> = HashMap::new();
+
+    // matrix column of every cell in the impute output, its line in `cells.txt`.
+    let shard_columns: HashMap<usize, usize> = shard_cells
+        .iter()
+        .enumerate()
+        .map(|(i, &x)| (x, i))
+        .collect();
+
+    let posterior_options = PosteriorOptions {
+        output: match mode {
+            RunMode::Predict => PosteriorOutput::Marks,
+            _ => PosteriorOutput::States,
+        },
+        checkpoint,
+    };
+
     info!("Starting forward backward");
-    //(0..num_chrs).rev().take(1).for_each(|chr_id| {
-    chrs.rev().for_each(|chr_id| {
-        let chr_name = format!("chr{}", chr_id+1);
-        let tids: Vec<u64> = frags.iter().map(|x| x.tid(&chr_name)).collect();
+    for chr_id in chrs.rev() {
+        let chr_start = Instant::now();
+        let chr_name = format!("chr{}", chr_id + 1);
+        let out_path = out_root.join(&chr_name);
+        if resume && out_path.join(posterior::DONE_FILE).exists() {
+            info!("Skipping completed {}", chr_name);
+            continue;
+        }
+
+        let chr_cells = get_remaining_cells(mode, resume, &out_path, &shard_cells, &common_cells);
+        let num_chr_cells = chr_cells.len();
+        if num_chr_cells == 0 {
+            info!("Found all cells of {} complete", chr_name);
+            write_done_marker(&out_path, num_shard_cells)?;
+            continue;
+        } else if num_chr_cells != num_shard_cells {
+            info!(
+                "Resuming {} with {} remaining cells",
+                chr_name, num_chr_cells
+            );
+        }
+
         info!("Working on {}", chr_name);
 
         let range = Range {
@@ -134,182 +658,591 @@ pub fn callback(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
             .iter_mut()
             .enumerate()
             .map(|(i, x)| {
-                let cell_records = x.fetch(
-                    tids[i],
+                x.fetch(
+                    &chr_name,
                     &range,
                     &vec_anchor_triplets.get(i).unwrap(),
+                    &vec_barcodes[i],
                     num_common_cells,
+                )
+            })
+            .collect::<Result<_, _>>()?;
+        let exp = Experiment::new(assay_data);
+
+        let chr_len = chr_lens[chr_id] as usize;
+        let masked_bins = blacklist.masked_bins(&chr_name, chr_len / WINDOW_SIZE + 1);
+        let pbar = get_progress_bar(num_chr_cells);
+        let chr = Chromosome {
+            out_path: &out_path,
+            len: chr_len,
+            masked_bins: &masked_bins,
+            cells: &chr_cells,
+            exp: &exp,
+            pbar: &pbar,
+        };
+
+        match mode {
+            RunMode::Evaluate => evaluate_chromosome(
+                &chr,
+                hmm.as_ref().unwrap(),
+                &holdout_hmms,
+                observation_options,
+                checkpoint,
+                num_threads,
+                &mut cell_histograms,
+            ),
+            RunMode::Impute => {
+                impute_chromosome(&chr, &shard_columns, observation_options, num_threads)?;
+                write_done_marker(&out_path, num_shard_cells)?;
+            }
+            RunMode::Posterior | RunMode::Predict => {
+                posterior_chromosome(
+                    &chr,
+                    hmm.as_ref().unwrap(),
+                    &common_cells,
+                    observation_options,
+                    posterior_options,
+                    num_threads,
                 );
+                write_done_marker(&out_path, num_shard_cells)?;
+            }
+        }
+        pbar.finish();
+
+        manifest.runs.last_mut().unwrap().add_chromosome(
+            &chr_name,
+            chr_len / WINDOW_SIZE + 1,
+            num_chr_cells,
+            chr_start,
+        );
+        manifest.write(&out_root)?;
+    }
+
+    if mode == RunMode::Evaluate {
+        write_evaluation(
+            &out_root,
+            hmm.as_ref().unwrap(),
+            &shard_cells,
+            &common_cells,
+            cell_histograms,
+        )?;
+    }
+    info!("All Done");
+
+    Ok(())
+}
+
+/// cells of the shard left to process on a chromosome. Only the posteriors
+/// are resumed cell by cell, since the files of the cells are renamed into
+/// place once fully written; the other modes redo every chromosome without
+/// a done marker.
+fn get_remaining_cells(
+    mode: RunMode,
+    resume: bool,
+    out_path: &std::path::Path,
+    shard_cells: &[usize],
+    common_cells: &[String],
+) -> Vec<usize> {
+    shard_cells
+        .iter()
+        .filter(|&&cell_id| {
+            !resume
+                || mode != RunMode::Posterior
+                || !out_path
+                    .join(format!("{}.bin", common_cells[cell_id]))
+                    .exists()
+        })
+        .copied()
+        .collect()
+}
+
+/// a chromosome being processed, with the signal of its remaining cells.
+struct Chromosome<'a> {
+    out_path: &'a std::path::Path,
+    len: usize,
+    masked_bins: &'a [bool],
+    cells: &'a [usize],
+    exp: &'a Experiment<ProbT>,
+    pbar: &'a ProgressBar,
+}
 
-                AssayRecords::new(cell_records)
+/// adds the held out mark predictions of the cells of a chromosome to their
+/// score histograms.
+fn evaluate_chromosome(
+    chr: &Chromosome,
+    hmm: &Hmm,
+    holdout_hmms: &[Hmm],
+    observation_options: ObservationOptions,
+    checkpoint: bool,
+    num_threads: usize,
+    cell_histograms: &mut HashMap<usize, Vec<ScoreHistogram>>,
+) {
+    let chunk_size = (chr.cells.len() + num_threads - 1) / num_threads;
+    crossbeam::scope(|scope| {
+        let handles: Vec<_> = chr
+            .cells
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move |_| {
+                    let mut cells = Vec::new();
+                    for &cell_id in chunk {
+                        let cell_data = chr.exp.get_cell_data(cell_id);
+                        let holdouts = quantify::run_holdout(
+                            cell_data.iter().collect(),
+                            hmm,
+                            holdout_hmms,
+                            chr.len,
+                            chr.masked_bins,
+                            observation_options,
+                            checkpoint,
+                        );
+
+                        // blacklisted bins are left out of the scores.
+                        let hists = holdouts
+                            .into_iter()
+                            .map(|(predictions, labels)| {
+                                let mut hist = ScoreHistogram::default();
+                                predictions
+                                    .into_iter()
+                                    .zip(labels.into_iter())
+                                    .zip(chr.masked_bins.iter())
+                                    .filter(|(_, &is_masked)| !is_masked)
+                                    .for_each(|((prediction, label), _)| {
+                                        hist.add(prediction, label)
+                                    });
+                                hist
+                            })
+                            .collect();
+                        cells.push((cell_id, hists));
+                        chr.pbar.inc(1);
+                    }
+                    cells
+                })
             })
             .collect();
-        let exp = Experiment::new(assay_data);
 
-        let pbar = ProgressBar::new(num_common_cells as u64);
-        pbar.set_style(
-            ProgressStyle::default_bar()
-                .template(
-                    "{spinner:.red} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos:>7}/{len:7} {msg}",
-                )
-                .progress_chars("╢▌▌░╟"),
-        );
+        for handle in handles {
+            evaluate::merge_cells(cell_histograms, handle.join().unwrap());
+        }
+    })
+    .unwrap();
+}
+
+/// writes the imputed signal of the cells of a chromosome, one bin by cell
+/// matrix per assay with a column per cell of the shard.
+fn impute_chromosome(
+    chr: &Chromosome,
+    shard_columns: &HashMap<usize, usize>,
+    observation_options: ObservationOptions,
+    num_threads: usize,
+) -> Result<(), Box<dyn Error>> {
+    std::fs::create_dir_all(chr.out_path)?;
+    let num_assays = chr.exp.num_assays();
+    let num_bins = chr.len / WINDOW_SIZE + 1;
 
-        let only_imputed = false;
-        if only_imputed {
-            let mut files: Vec<std::io::BufWriter<std::fs::File>> = (0..num_assays).map(|id| {
-                let path = std::path::Path::new("/mnt/scratch1/avi/Indus/data/out").join(&chr_name).join(format!("{}.txt", id));
-                let f = std::fs::File::create(path).unwrap();
-                std::io::BufWriter::new(f)
-            }).collect();
-
-            (0..num_common_cells).for_each(|cell_id| {
-                pbar.inc(1);
-                let cell_data = exp.get_cell_data(cell_id);
-                let itrees: Vec<IntervalTree<u32, ProbT>> = cell_data
-                    .into_iter()
-                    .map(|cell_records| {
-                        let mut tree = IntervalTree::new();
-                        for record in cell_records.records() {
-                            tree.insert(record.range(), record.id());
+    let mut writers: Vec<MatrixMarketWriter> = (0..num_assays)
+        .map(|assay| {
+            let out_file = chr.out_path.join(format!("{}.mtx.gz", assay));
+            MatrixMarketWriter::new(out_file, num_bins, shard_columns.len())
+        })
+        .collect::<Result<_, _>>()?;
+
+    // cells are quantified in batches written in order, so that
+    // only the entries of one batch are held in memory.
+    for batch in chr.cells.chunks(num_threads * IMPUTE_BATCH_CELLS) {
+        let chunk_size = (batch.len() + num_threads - 1) / num_threads;
+        let batch_entries = crossbeam::scope(|scope| {
+            let handles: Vec<_> = batch
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    scope.spawn(move |_| {
+                        // (bin, column, signal) entries of every assay.
+                        let mut entries = vec![Vec::new(); num_assays];
+                        for &cell_id in chunk {
+                            let cell_data = chr.exp.get_cell_data(cell_id);
+                            let observations = quantify::get_observations(
+                                cell_data.iter().collect(),
+                                chr.len,
+                                chr.masked_bins,
+                                observation_options.mode,
+                            );
+                            let col = shard_columns[&cell_id];
+                            for (bin, observation) in observations.into_iter() {
+                                for (assay, signal) in observation.into_iter().enumerate() {
+                                    if signal != 0.0 {
+                                        entries[assay].push((bin, col, signal));
+                                    }
+                                }
+                            }
+                            chr.pbar.inc(1);
                         }
-                        tree
+                        entries
                     })
-                    .collect();
-
-                let get_obv_list = |start: usize, end: usize| {
-                    let observation_list: Vec<Vec<ProbT>> = (start..end)
-                        .step_by(WINDOW_SIZE)
-                        .map(|qstart| {
-                            let qrange = qstart as u32..(qstart + WINDOW_SIZE) as u32;
-                            let cts: Vec<ProbT> = itrees
-                                .iter()
-                                .map(|tree| {
-                                    let vals: Vec<ProbT> = tree.find(&qrange).map(|x| *x.data()).collect();
-                                    vals.iter().sum()
-                                })
-                                .collect();
-                            cts
-                        })
-                        .collect();
-                    observation_list
-                };
-
-                let observation_list = get_obv_list(0, chr_lens[chr_id] as usize);
-                for observation in observation_list {
-                    (0..observation.len()).for_each(|id| {
-                        if observation[id] != 0.0 {
-                            writeln!(files[id], "{:.8}", observation[id]).unwrap();
-                        }
-                    });
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect::<Vec<_>>()
+        })
+        .unwrap();
+
+        for chunk_entries in batch_entries {
+            for (assay, mut entries) in chunk_entries.into_iter().enumerate() {
+                entries.sort_unstable_by_key(|x| (x.1, x.0));
+                for (row, col, val) in entries {
+                    writers[assay].push(row, col, val)?;
+                }
+            }
+        }
+    }
+    for writer in writers {
+        writer.finish()?;
+    }
+
+    Ok(())
+}
+
+/// writes the posteriors of the cells of a chromosome, one file per cell.
+fn posterior_chromosome(
+    chr: &Chromosome,
+    hmm: &Hmm,
+    common_cells: &[String],
+    observation_options: ObservationOptions,
+    posterior_options: PosteriorOptions,
+    num_threads: usize,
+) {
+    std::fs::create_dir_all(chr.out_path).unwrap();
+
+    let q = Arc::new(ArrayQueue::<usize>::new(chr.cells.len()));
+    //(0..num_common_cells).filter(|&x| x == 2840).for_each(|x| q.push(x).unwrap());
+    chr.cells.iter().for_each(|&x| q.push(x).unwrap());
+
+    let (tx, rx) = mpsc::sync_channel(num_threads);
+
+    let arc_hmm = Arc::new(hmm);
+    let arc_exp = Arc::new(chr.exp);
+    let arc_out_path = Arc::new(chr.out_path);
+    let arc_common_cells = Arc::new(common_cells);
+
+    let num_states = hmm.num_states();
+    let num_outputs = match posterior_options.output {
+        PosteriorOutput::Marks => hmm.num_marks(),
+        PosteriorOutput::Mark(_) => 1,
+        PosteriorOutput::States => num_states,
+    };
+    let chr_len = chr.len;
+    let arc_masked_bins = Arc::new(chr.masked_bins);
+    crossbeam::scope(|scope| {
+        for _ in 0..num_threads {
+            let tx = tx.clone();
+            let reader = Arc::clone(&q);
+            let arc_hmm = Arc::clone(&arc_hmm);
+            let arc_exp = Arc::clone(&arc_exp);
+            let arc_out_path = Arc::clone(&arc_out_path);
+            let arc_common_cells = Arc::clone(&arc_common_cells);
+            let arc_masked_bins = Arc::clone(&arc_masked_bins);
+
+            let mut posterior = Vec::with_capacity(chr_len * num_states / 400);
+
+            scope.spawn(move |_| loop {
+                match reader.pop() {
+                    Some(cell_id) => {
+                        posterior.clear();
+                        let cell_data = arc_exp.get_cell_data(cell_id);
+                        quantify::run_fwd_bkw(
+                            cell_data.iter().collect(),
+                            &arc_hmm,
+                            &mut posterior,
+                            chr_len,
+                            &arc_masked_bins,
+                            observation_options,
+                            posterior_options,
+                        )
+                        .unwrap();
+
+                        let out_file =
+                            arc_out_path.join(format!("{}.bin", arc_common_cells[cell_id]));
+                        let bin_mat = CellPosterior::from_triplets(
+                            &posterior,
+                            chr_len / 200 + 1,
+                            num_outputs,
+                        )
+                        .to_bytes();
+
+                        tx.send(Some((bin_mat, out_file)))
+                            .expect("Could not send mid data!");
+                    }
+                    None => {
+                        tx.send(None).expect("Could not send end data!");
+                        break;
+                    }
                 }
             });
-        } else {
-            let out_path =
-                std::path::Path::new(sub_m.value_of("output").unwrap()).join(&chr_name);
-            std::fs::create_dir_all(&out_path).unwrap();
-
-            let q = Arc::new(ArrayQueue::<usize>::new(num_common_cells));
-            //(0..num_common_cells).filter(|&x| x == 2840).for_each(|x| q.push(x).unwrap());
-            (0..num_common_cells).for_each(|x| q.push(x).unwrap());
-
-            let (tx, rx) = mpsc::sync_channel(num_threads);
-
-            let arc_hmm = Arc::new(&hmm);
-            let arc_exp = Arc::new(&exp);
-            let arc_out_path = Arc::new(&out_path);
-            let arc_common_cells = Arc::new(&common_cells);
-
-            let num_states = hmm.num_states();
-            let chr_len = chr_lens[chr_id] as usize;
-            crossbeam::scope(|scope| {
-                for _ in 0..num_threads {
-                    let tx = tx.clone();
-                    let reader = Arc::clone(&q);
-                    let arc_hmm = Arc::clone(&arc_hmm);
-                    let arc_exp = Arc::clone(&arc_exp);
-                    let arc_out_path = Arc::clone(&arc_out_path);
-                    let arc_common_cells = Arc::clone(&arc_common_cells);
-
-                    let mut posterior = Vec::with_capacity(chr_len * num_states / 400);
-                    let mut fprob = vec![vec![0.0; arc_hmm.num_states()]; (chr_len / 200) + 1];
-
-                    scope.spawn(move |_| loop {
-                        match reader.pop() {
-                            Some(cell_id) => {
-                                posterior.clear();
-                                let cell_data = arc_exp.get_cell_data(cell_id);
-                                quantify::run_fwd_bkw(cell_data, &arc_hmm, &mut fprob, &mut posterior, chr_len).unwrap();
-
-                                let out_file = arc_out_path.join(format!("{}.bin", arc_common_cells[cell_id]));
-                                let num_posteriors = posterior.len();
-                                let mut bin_mat: Vec<u8> = vec![(
-                                    num_posteriors as u32).to_le_bytes(),
-                                    ((chr_len / 200 + 1) as u32).to_le_bytes(),
-                                    (num_states as u32).to_le_bytes()
-                                ].concat();
-
-                                let mut indices = posterior.iter().map(|x| (x.0 as u32).to_le_bytes()).collect::<Vec<[u8; 4]>>().concat();
-                                let mut state = posterior.iter().map(|x| (x.1 as u8).to_le_bytes()).collect::<Vec<[u8; 1]>>().concat();
-                                let mut value = posterior.iter().map(|x| ( (x.2 * 100.0).round() as u8).to_le_bytes()).collect::<Vec<[u8; 1]>>().concat();
-                                bin_mat.append(&mut value);
-                                bin_mat.append(&mut state);
-                                bin_mat.append(&mut indices);
-
-                                tx.send(Some((bin_mat, out_file)))
-                                    .expect("Could not send mid data!");
-                            }
-                            None => {
-                                tx.send(None).expect("Could not send end data!");
-                                break;
-                            }
+        }
+
+        let mut dead_thread_count = 0;
+        for out_data in rx.iter() {
+            match out_data {
+                Some((mat, out_file)) => {
+                    chr.pbar.inc(1);
+                    write_binary(out_file, mat).unwrap();
+                    //write_matrix_market(out_file, &mat.to_csr()).unwrap();
+                } // end-Some
+                None => {
+                    dead_thread_count += 1;
+                    if dead_thread_count == num_threads {
+                        drop(tx);
+
+                        for out_data in rx.iter() {
+                            chr.pbar.inc(1);
+                            out_data.map_or((), |(mat, out_file)| {
+                                write_binary(out_file, mat).unwrap();
+                                //write_matrix_market(out_file, &mat.to_csr()).unwrap();
+                            });
                         }
-                    });
-                }
+                        break;
+                    }
+                } // end-None
+            } // end-match
+        } // end-for
+    })
+    .unwrap(); //end crossbeam
+}
 
-                let mut dead_thread_count = 0;
-                for out_data in rx.iter() {
-                    match out_data {
-                        Some((mat, out_file)) => {
-                            pbar.inc(1);
-                            write_binary(out_file, mat).unwrap();
-                            //write_matrix_market(out_file, &mat.to_csr()).unwrap();
-                        } // end-Some
-                        None => {
-                            dead_thread_count += 1;
-                            if dead_thread_count == num_threads {
-                                drop(tx);
-
-                                for out_data in rx.iter() {
-                                    pbar.inc(1);
-                                    out_data.map_or((), |(mat, out_file)| {
-                                        write_binary(out_file, mat).unwrap();
-                                        //write_matrix_market(out_file, &mat.to_csr()).unwrap();
-                                    });
-                                }
-                                break;
-                            }
-                        } // end-None
-                    } // end-match
-                } // end-for
-            })
-            .unwrap(); //end crossbeam
-        }
-        pbar.finish();
-    });
-    info!("All Done");
+/// writes the held out mark scores of the shard cells and logs the overall
+/// ones.
+fn write_evaluation(
+    out_root: &std::path::Path,
+    hmm: &Hmm,
+    shard_cells: &[usize],
+    common_cells: &[String],
+    mut cell_histograms: HashMap<usize, Vec<ScoreHistogram>>,
+) -> Result<(), Box<dyn Error>> {
+    let marks: Vec<String> = hmm
+        .assays()
+        .iter()
+        .map(|&x| hmm.mark_names()[x].clone())
+        .collect();
+    let cells: Vec<(String, Vec<ScoreHistogram>)> = shard_cells
+        .iter()
+        .filter_map(|&cell_id| {
+            cell_histograms
+                .remove(&cell_id)
+                .map(|x| (common_cells[cell_id].clone(), x))
+        })
+        .collect();
+
+    let overall = evaluate::write_report(&out_root.join(EVALUATION_FILE), &marks, &cells)?;
+    for (mark, hist) in marks.iter().zip(overall.iter()) {
+        info!(
+            "Held out {}: AUROC {}, AUPRC {}",
+            mark,
+            evaluate::format_metric(hist.auroc()),
+            evaluate::format_metric(hist.auprc())
+        );
+    }
 
     Ok(())
 }
 
-pub fn write_binary(path: std::path::PathBuf, mat: Vec<u8>) -> Result<(), Box<dyn Error>> {
-    let f = std::fs::File::create(path)?;
-    //let mut file = GzEncoder::new(f, Compression::default());
-    let mut file = std::io::BufWriter::new(f);
-    //let mut file = std::io::BufWriter::new(snap::write::FrameEncoder::new(f));
+/// progress bar of the per chromosome and per cell loops.
+pub fn get_progress_bar(len: usize) -> ProgressBar {
+    let pbar = ProgressBar::new(len as u64);
+    pbar.set_style(
+        ProgressStyle::default_bar()
+            .template(
+                "{spinner:.red} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos:>7}/{len:7} {msg}",
+            )
+            .progress_chars("╢▌▌░╟"),
+    );
 
-    // entries
-    file.write_all(&mat)?;
+    pbar
+}
 
-    Ok(())
+////////////////////////////////////////////
+/// MatrixMarketWriter
+/// bin by cell gzipped matrix market file written as the entries come.
+/// The entries go to a temporary body file, and `finish` prepends the
+/// header with their count as a separate gzip member before renaming
+/// the whole into place like `write_binary`.
+////////////////////////////////////////////
+struct MatrixMarketWriter {
+    path: std::path::PathBuf,
+    body_path: std::path::PathBuf,
+    body: std::io::BufWriter<GzEncoder<std::fs::File>>,
+    num_rows: usize,
+    num_cols: usize,
+    num_entries: usize,
+}
+
+impl MatrixMarketWriter {
+    fn new(
+        path: std::path::PathBuf,
+        num_rows: usize,
+        num_cols: usize,
+    ) -> Result<MatrixMarketWriter, Box<dyn Error>> {
+        let mut body_path = path.clone().into_os_string();
+        body_path.push(".body.tmp");
+        let body_path = std::path::PathBuf::from(body_path);
+
+        let f = std::fs::File::create(&body_path)?;
+        Ok(MatrixMarketWriter {
+            path,
+            body_path,
+            body: std::io::BufWriter::new(GzEncoder::new(f, Compression::default())),
+            num_rows,
+            num_cols,
+            num_entries: 0,
+        })
+    }
+
+    /// 0-indexed row and column of an entry.
+    fn push(&mut self, row: usize, col: usize, val: ProbT) -> Result<(), Box<dyn Error>> {
+        writeln!(self.body, "{} {} {}", row + 1, col + 1, val)?;
+        self.num_entries += 1;
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(), Box<dyn Error>> {
+        self.body
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .finish()?;
+
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = std::path::PathBuf::from(tmp_path);
+
+        // concatenated gzip members decompress as a single stream.
+        let f = std::fs::File::create(&tmp_path)?;
+        let mut header = GzEncoder::new(f, Compression::default());
+        writeln!(header, "%%MatrixMarket matrix coordinate real general")?;
+        writeln!(
+            header,
+            "{} {} {}",
+            self.num_rows, self.num_cols, self.num_entries
+        )?;
+        let mut f = header.finish()?;
+        std::io::copy(&mut std::fs::File::open(&self.body_path)?, &mut f)?;
+        f.sync_all()?;
+
+        std::fs::rename(tmp_path, self.path)?;
+        std::fs::remove_file(self.body_path)?;
+        Ok(())
+    }
+}
+
+/// marks all the cells of a chromosome as processed.
+fn write_done_marker(chr_path: &std::path::Path, num_cells: usize) -> Result<(), Box<dyn Error>> {
+    std::fs::create_dir_all(chr_path)?;
+    write_binary(
+        chr_path.join(posterior::DONE_FILE),
+        format!("{}\n", num_cells).into_bytes(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{App, Arg};
+
+    #[test]
+    fn test_parse_anchor() {
+        let line = "r1\tAAA-1\t0.5";
+        let delimiter = anchor::get_delimiter(line);
+        assert_eq!(delimiter, Some('\t'));
+        assert_eq!(parse_anchor(line, delimiter), Ok(("r1", "AAA-1", 0.5)));
+
+        // row names and quotes as written by R.
+        let line = "\"1\",\"r1\",\"AAA-1\",0.5";
+        let delimiter = anchor::get_delimiter(line);
+        assert_eq!(delimiter, Some(','));
+        assert_eq!(parse_anchor(line, delimiter), Ok(("r1", "AAA-1", 0.5)));
+
+        let line = "r1  AAA-1 0.5";
+        assert_eq!(anchor::get_delimiter(line), None);
+        assert_eq!(parse_anchor(line, None), Ok(("r1", "AAA-1", 0.5)));
+
+        for line in &["r1 AAA-1", "r1 AAA-1 x", "r1 AAA-1 -1", "r1 AAA-1 inf"] {
+            assert!(parse_anchor(line, None).is_err(), "{}", line);
+        }
+        assert!(parse_anchor(",AAA-1,0.5", Some(',')).is_err());
+
+        assert!(is_score("r1,AAA-1,0.5", Some(',')));
+        assert!(!is_score("cell1,cell2,score", Some(',')));
+    }
+
+    #[test]
+    fn test_read_anchor_file() {
+        let common_cells: HashMap<String, u32> = vec![("r1".to_string(), 0), ("r2".to_string(), 1)]
+            .into_iter()
+            .collect();
+        let path = std::env::temp_dir().join("schrom_test_anchors.csv");
+        let read = |content: &str, skip_unknown_cells: bool| {
+            std::fs::write(&path, content).unwrap();
+            read_anchor_file(&path, &common_cells, skip_unknown_cells)
+        };
+
+        // the delimiter and the header are detected on the first record.
+        let content = "\n# anchors\n\"\",\"cell1\",\"cell2\",\"score\"\n\"1\",\"r1\",\"AAA-1\",0.5\n\n\"2\",\"r2\",\"CCC-1\",0.25\n";
+        let anchors = read(content, false).unwrap();
+        assert_eq!(
+            anchors,
+            [
+                (0, "AAA-1".to_string(), 0.5),
+                (1, "CCC-1".to_string(), 0.25)
+            ]
+        );
+        let anchors = read("r2 AAA-1 1\nr1 CCC-1 2\n", false).unwrap();
+        assert_eq!(
+            anchors,
+            [(1, "AAA-1".to_string(), 1.0), (0, "CCC-1".to_string(), 2.0)]
+        );
+
+        // only the first record can be a header, and not the only one.
+        let err = read("r1 AAA-1 1\ncell1 cell2 score\n", false).unwrap_err();
+        assert!(err.to_string().contains("line 2"), "{}", err);
+        let err = read("\ncell1 cell2 score\n", false).unwrap_err();
+        assert!(err.to_string().contains("line 2"), "{}", err);
+        assert!(read("r1 AAA-1 1 2 3\nr1 CCC-1 2\n", false).is_err());
+
+        assert!(read("r1 AAA-1 1\nr3 CCC-1 2\n", false).is_err());
+        let anchors = read("r1 AAA-1 1\nr3 CCC-1 2\n", true).unwrap();
+        assert_eq!(anchors, [(0, "AAA-1".to_string(), 1.0)]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_shard_cells() {
+        let common_cells: Vec<String> = (0..10).map(|x| format!("r{}", x)).collect();
+        let get_shard = |shard: &str| {
+            let sub_m = App::new("test")
+                .arg(Arg::with_name("shard").long("shard").takes_value(true))
+                .get_matches_from(vec!["test", "--shard", shard]);
+            get_shard_cells(&sub_m, &common_cells)
+        };
+
+        // the shards partition the cells in order.
+        for num_shards in 1..=12 {
+            let mut cells = Vec::new();
+            for index in 1..=num_shards {
+                let (shard_cells, shard) = get_shard(&format!("{}/{}", index, num_shards)).unwrap();
+                assert_eq!(shard, Some((index, num_shards)));
+                cells.extend(shard_cells);
+            }
+            assert_eq!(cells, (0..10).collect::<Vec<usize>>());
+        }
+
+        for shard in &["0/3", "4/3", "1/0", "1-3", "1/3/1", "a/3"] {
+            assert!(get_shard(shard).is_err(), "{}", shard);
+        }
+
+        let sub_m = App::new("test").get_matches_from(vec!["test"]);
+        let (cells, shard) = get_shard_cells(&sub_m, &common_cells).unwrap();
+        assert_eq!(cells, (0..10).collect::<Vec<usize>>());
+        assert_eq!(shard, None);
+    }
 }
@@ -4,47 +4,334 @@ extern crate log;
 use clap::{App, Arg, SubCommand};
 use std::error::Error;
 
+mod anchor;
+mod barcode;
+mod blacklist;
 mod config;
+mod evaluate;
+mod file;
 mod fragment;
 mod hmm;
+mod manifest;
+mod matrix;
+mod merge;
 mod model;
+mod posterior;
 mod quantify;
 mod record;
+mod subset;
 mod transform;
 
+/// inputs of the subcommands fetching the anchor imputed signal.
+/// `model_required` is false for impute, which reads only the anchors.
+fn add_hmm_args<'a, 'b>(app: App<'a, 'b>, model_required: bool) -> App<'a, 'b> {
+    app
+        .arg(
+            Arg::with_name("fragments")
+                .long("fragments")
+                .short("f")
+                .takes_value(true)
+                .required(true)
+                .multiple(true)
+                .help("path to the fragment files, indexed BAM/CRAM files or count matrix directories, one per assay. Libraries of an assay are comma separated, each optionally followed by :<barcode suffix>."),
+        )
+        .arg(
+            Arg::with_name("min_fragment_length")
+                .long("min_fragment_length")
+                .takes_value(true)
+                .help("minimum length of the fragments used for the observations"),
+        )
+        .arg(
+            Arg::with_name("max_fragment_length")
+                .long("max_fragment_length")
+                .takes_value(true)
+                .help("maximum length of the fragments used for the observations"),
+        )
+        .arg(
+            Arg::with_name("depth_norm")
+                .long("depth_norm")
+                .takes_value(true)
+                .multiple(true)
+                .possible_values(&["library", "suffix", "query_cell", "reference_cell"])
+                .help("scale the signal of the libraries (fragment files), barcode suffixes, query cells and/or reference cells of an assay to their mean depth before thresholding"),
+        )
+        .arg(
+            Arg::with_name("normalize_libraries")
+                .long("normalize_libraries")
+                .help("same as `--depth_norm library`"),
+        )
+        .arg(
+            Arg::with_name("threshold_mode")
+                .long("threshold_mode")
+                .takes_value(true)
+                .possible_values(&["fixed", "poisson"])
+                .default_value("fixed")
+                .help("binarise the observations with the fixed per mark thresholds, or per cell thresholds from a Poisson background of the cell's own signal"),
+        )
+        .arg(
+            Arg::with_name("threshold_pvalue")
+                .long("threshold_pvalue")
+                .takes_value(true)
+                .default_value("0.01")
+                .help("p-value of the poisson threshold mode"),
+        )
+        .arg(
+            Arg::with_name("count_duplicates")
+                .long("count_duplicates")
+                .help("weight the fragments by the duplicate count (5th) column of the fragment files"),
+        )
+        .arg(
+            Arg::with_name("count_mode")
+                .long("count_mode")
+                .takes_value(true)
+                .possible_values(&["overlap", "cutsites", "midpoint"])
+                .default_value("overlap")
+                .help("count a fragment in every bin it overlaps, in the bins of its two Tn5 cut sites or in the bin of its midpoint"),
+        )
+        .arg(
+            Arg::with_name("cut_shift_start")
+                .long("cut_shift_start")
+                .takes_value(true)
+                .allow_hyphen_values(true)
+                .help("shift added to the fragment start cut site in cutsites mode, e.g. 4"),
+        )
+        .arg(
+            Arg::with_name("cut_shift_end")
+                .long("cut_shift_end")
+                .takes_value(true)
+                .allow_hyphen_values(true)
+                .help("shift added to the fragment end cut site in cutsites mode, e.g. -5"),
+        )
+        .arg(
+            Arg::with_name("blacklist")
+                .long("blacklist")
+                .takes_value(true)
+                .help("path to a BED file of regions masked from the observations"),
+        )
+        .arg(
+            Arg::with_name("barcode_prefix")
+                .long("barcode_prefix")
+                .takes_value(true)
+                .help("prefix added to the fragment barcodes to match the anchor barcodes"),
+        )
+        .arg(
+            Arg::with_name("barcode_suffix")
+                .long("barcode_suffix")
+                .takes_value(true)
+                .help("suffix added to the fragment barcodes to match the anchor barcodes"),
+        )
+        .arg(
+            Arg::with_name("strip_barcode_suffix")
+                .long("strip_barcode_suffix")
+                .help("remove the trailing -<suffix> of the fragment and the anchor barcodes before matching"),
+        )
+        .arg(
+            Arg::with_name("barcode_tag")
+                .long("barcode_tag")
+                .takes_value(true)
+                .default_value("CB")
+                .help("BAM/CRAM tag with the cellular barcode"),
+        )
+        .arg(
+            Arg::with_name("min_mapq")
+                .long("min_mapq")
+                .takes_value(true)
+                .default_value("30")
+                .help("minimum mapping quality of BAM/CRAM alignments"),
+        )
+        .arg(
+            Arg::with_name("keep_duplicates")
+                .long("keep_duplicates")
+                .help("keep BAM/CRAM alignments flagged as duplicates"),
+        )
+        .arg(
+            Arg::with_name("keep_secondary")
+                .long("keep_secondary")
+                .help("keep secondary and supplementary BAM/CRAM alignments"),
+        )
+        .arg(
+            Arg::with_name("reference")
+                .long("reference")
+                .takes_value(true)
+                .help("path to the reference fasta of CRAM files"),
+        )
+        .arg(
+            Arg::with_name("anchors")
+                .long("anchors")
+                .short("a")
+                .takes_value(true)
+                .required_unless("same_cell")
+                .conflicts_with("same_cell")
+                .multiple(true)
+                .help("path to the anchors files. [Same order as fragments]"),
+        )
+        .arg(
+            Arg::with_name("same_cell")
+                .long("same_cell")
+                .help("all the assays are measured in the same cells, identified by the barcodes of the common cells, instead of anchors"),
+        )
+        .arg(
+            Arg::with_name("knn_graph")
+                .long("knn_graph")
+                .takes_value(true)
+                .requires("same_cell")
+                .help("cell, neighbour and weight of the kNN graph smoothing the observations in the same cell mode"),
+        )
+        .arg(
+            Arg::with_name("knn_self_weight")
+                .long("knn_self_weight")
+                .takes_value(true)
+                .requires("knn_graph")
+                .help("weight of a cell's own fragments relative to the kNN edge weights (default 1)"),
+        )
+        .arg(
+            Arg::with_name("anchor_weighting")
+                .long("anchor_weighting")
+                .takes_value(true)
+                .possible_values(&["raw", "mean", "normalized", "softmax"])
+                .default_value("mean")
+                .help("weights of the query cells of a reference cell: the raw scores, the scores over the number of anchors, the scores over their sum or their softmax"),
+        )
+        .arg(
+            Arg::with_name("softmax_temperature")
+                .long("softmax_temperature")
+                .takes_value(true)
+                .default_value("1")
+                .help("temperature of the softmax anchor weighting"),
+        )
+        .arg(
+            Arg::with_name("top_k_anchors")
+                .long("top_k_anchors")
+                .takes_value(true)
+                .help("only keep the top scoring anchors of every reference cell"),
+        )
+        .arg(
+            Arg::with_name("min_anchor_score")
+                .long("min_anchor_score")
+                .takes_value(true)
+                .help("drop the anchors with a lower score"),
+        )
+        .arg(
+            Arg::with_name("skip_unknown_cells")
+                .long("skip_unknown_cells")
+                .help("skip the anchors of cells missing from the common cells instead of erroring"),
+        )
+        .arg(
+            Arg::with_name("threads")
+                .long("threads")
+                .short("t")
+                .takes_value(true)
+                .required(true)
+                .help("number of threads to use"),
+        )
+        .arg(
+            Arg::with_name("onlyone")
+                .help("quantify only chromosome one")
+                .long("onlyone"),
+        )
+        .arg(
+            Arg::with_name("model")
+                .long("model")
+                .short("m")
+                .takes_value(true)
+                .required(model_required)
+                .help("path to the chromeHMM model.txt file"),
+        )
+        .arg(
+            Arg::with_name("unmeasured_marks")
+                .long("unmeasured_marks")
+                .takes_value(true)
+                .multiple(true)
+                .help("marks of the model without an assay, the fragments and anchors are given for the remaining marks in model order"),
+        )
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .short("o")
+                .takes_value(true)
+                .required(true)
+                .help("path to the output directory"),
+        )
+        .arg(
+            Arg::with_name("common_cells")
+                .long("common_cells")
+                .short("c")
+                .takes_value(true)
+                .required(true)
+                .help("path to the file with cellular barcodes of common assay"),
+        )
+        .arg(
+            Arg::with_name("shard")
+                .long("shard")
+                .takes_value(true)
+                .conflicts_with("cells_subset")
+                .help("process only the i-th of n contiguous slices of the common cells, as i/n (1-indexed)"),
+        )
+        .arg(
+            Arg::with_name("checkpoint")
+                .long("checkpoint")
+                .help("keep the forward probabilities of only every sqrt(n)-th run of identical bins and recompute the rest during the backward pass"),
+        )
+        .arg(
+            Arg::with_name("checksum")
+                .long("checksum")
+                .help("record the CRC32 checksum of the input files in the manifest instead of only their size and modification time, reading every input file once more"),
+        )
+        .arg(
+            Arg::with_name("resume")
+                .long("resume")
+                .help("skip the chromosomes and cells completed by an earlier run into the same output directory"),
+        )
+        .arg(
+            Arg::with_name("cells_subset")
+                .long("cells_subset")
+                .takes_value(true)
+                .help("path to the file with the subset of common cells to process"),
+        )
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
     let matches = App::new("schrom")
         .version("0.1.0")
         .author("Avi Srivastava, Bingjie Zhang, Rahul Satija")
         .about("Generate summary stats for multimodal data.")
+        .subcommand(add_hmm_args(
+            SubCommand::with_name("hmm").about("A subcommand to run hmm."),
+            true,
+        ))
+        .subcommand(add_hmm_args(
+            SubCommand::with_name("impute")
+                .about("A subcommand to write the anchor imputed signal of every mark."),
+            false,
+        ))
+        .subcommand(add_hmm_args(
+            SubCommand::with_name("predict")
+                .about("A subcommand to write the mark probabilities predicted by the hmm."),
+            true,
+        ))
+        .subcommand(add_hmm_args(
+            SubCommand::with_name("evaluate")
+                .about("A subcommand to score the prediction of every mark with its assay held out."),
+            true,
+        ))
         .subcommand(
-            SubCommand::with_name("hmm")
-                .about("A subcommand to run hmm.")
-                .arg(
-                    Arg::with_name("fragments")
-                        .long("fragments")
-                        .short("f")
-                        .takes_value(true)
-                        .required(true)
-                        .multiple(true)
-                        .help("path to the fragment files."),
-                )
+            SubCommand::with_name("transform")
+                .about("A subcommand to transform long form matrices to short.")
                 .arg(
-                    Arg::with_name("anchors")
-                        .long("anchors")
-                        .short("a")
+                    Arg::with_name("in_directory")
+                        .long("in_directory")
+                        .short("i")
                         .takes_value(true)
                         .required(true)
-                        .multiple(true)
-                        .help("path to the anchors files. [Same order as fragments]"),
+                        .help("path to the scChromHMM output directory"),
                 )
                 .arg(
-                    Arg::with_name("threads")
-                        .long("threads")
-                        .short("t")
+                    Arg::with_name("out_directory")
+                        .long("out_directory")
+                        .short("o")
                         .takes_value(true)
                         .required(true)
-                        .help("number of threads to use"),
+                        .help("path to the scChromHMM output directory"),
                 )
                 .arg(
                     Arg::with_name("onlyone")
@@ -52,40 +339,64 @@ fn main() -> Result<(), Box<dyn Error>> {
                         .long("onlyone"),
                 )
                 .arg(
-                    Arg::with_name("model")
-                        .long("model")
-                        .short("m")
+                    Arg::with_name("common_cells")
+                        .long("common_cells")
+                        .short("c")
+                        .takes_value(true)
+                        .help("path to the file with cellular barcodes of common assay. [default: <in_directory>/cells.txt]"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("subset")
+                .about("A subcommand to subset hmm or transform output by cells, chromosomes or states.")
+                .arg(
+                    Arg::with_name("in_directory")
+                        .long("in_directory")
+                        .short("i")
                         .takes_value(true)
                         .required(true)
-                        .help("path to the chromeHMM model.txt file"),
+                        .help("path to the scChromHMM hmm or transform output directory"),
                 )
                 .arg(
-                    Arg::with_name("output")
-                        .long("output")
+                    Arg::with_name("out_directory")
+                        .long("out_directory")
                         .short("o")
                         .takes_value(true)
                         .required(true)
-                        .help("path to the output directory"),
+                        .help("path to the subset output directory"),
                 )
                 .arg(
-                    Arg::with_name("common_cells")
-                        .long("common_cells")
-                        .short("c")
+                    Arg::with_name("cells")
+                        .long("cells")
                         .takes_value(true)
-                        .required(true)
-                        .help("path to the file with cellular barcodes of common assay"),
+                        .help("path to the file with cellular barcodes to keep (default: all)"),
+                )
+                .arg(
+                    Arg::with_name("chromosomes")
+                        .long("chromosomes")
+                        .takes_value(true)
+                        .multiple(true)
+                        .help("chromosomes to keep (default: all)"),
+                )
+                .arg(
+                    Arg::with_name("states")
+                        .long("states")
+                        .takes_value(true)
+                        .multiple(true)
+                        .help("1-indexed states to keep (default: all)"),
                 ),
         )
         .subcommand(
-            SubCommand::with_name("transform")
-                .about("A subcommand to transform long form matrices to short.")
+            SubCommand::with_name("merge")
+                .about("A subcommand to merge transform output of runs over disjoint cells.")
                 .arg(
-                    Arg::with_name("in_directory")
-                        .long("in_directory")
+                    Arg::with_name("in_directories")
+                        .long("in_directories")
                         .short("i")
                         .takes_value(true)
                         .required(true)
-                        .help("path to the scChromHMM output directory"),
+                        .multiple(true)
+                        .help("path to the scChromHMM transform output directories"),
                 )
                 .arg(
                     Arg::with_name("out_directory")
@@ -93,20 +404,73 @@ fn main() -> Result<(), Box<dyn Error>> {
                         .short("o")
                         .takes_value(true)
                         .required(true)
-                        .help("path to the scChromHMM output directory"),
+                        .help("path to the merged output directory"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("anchors")
+                .about("A subcommand to build the anchors of the query cells onto the reference cells from a shared embedding.")
+                .arg(
+                    Arg::with_name("reference")
+                        .long("reference")
+                        .short("r")
+                        .takes_value(true)
+                        .required(true)
+                        .help("path to the embedding of the reference cells, cell name followed by the coordinates"),
                 )
                 .arg(
-                    Arg::with_name("onlyone")
-                        .help("quantify only chromosome one")
-                        .long("onlyone"),
+                    Arg::with_name("query")
+                        .long("query")
+                        .short("q")
+                        .takes_value(true)
+                        .required(true)
+                        .help("path to the embedding of the query cells, cell barcode followed by the coordinates"),
                 )
                 .arg(
-                    Arg::with_name("common_cells")
-                        .long("common_cells")
-                        .short("c")
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("o")
                         .takes_value(true)
                         .required(true)
-                        .help("path to the file with cellular barcodes of common assay."),
+                        .help("path to the output anchors file"),
+                )
+                .arg(
+                    Arg::with_name("neighbors")
+                        .long("neighbors")
+                        .short("k")
+                        .takes_value(true)
+                        .default_value("10")
+                        .help("number of query cells anchored to every reference cell"),
+                )
+                .arg(
+                    Arg::with_name("method")
+                        .long("method")
+                        .takes_value(true)
+                        .possible_values(&["exact", "approximate"])
+                        .default_value("exact")
+                        .help("exact or random projection forest nearest neighbour search"),
+                )
+                .arg(
+                    Arg::with_name("num_trees")
+                        .long("num_trees")
+                        .takes_value(true)
+                        .default_value("10")
+                        .help("number of random projection trees of the approximate search"),
+                )
+                .arg(
+                    Arg::with_name("seed")
+                        .long("seed")
+                        .takes_value(true)
+                        .default_value("0")
+                        .help("seed of the random projection trees"),
+                )
+                .arg(
+                    Arg::with_name("threads")
+                        .long("threads")
+                        .short("t")
+                        .takes_value(true)
+                        .default_value("1")
+                        .help("number of threads to use"),
                 ),
         )
         .get_matches();
@@ -116,9 +480,33 @@ fn main() -> Result<(), Box<dyn Error>> {
         hmm::callback(&sub_m)?
     }
 
+    if let Some(sub_m) = matches.subcommand_matches("impute") {
+        hmm::impute_callback(&sub_m)?
+    }
+
+    if let Some(sub_m) = matches.subcommand_matches("predict") {
+        hmm::predict_callback(&sub_m)?
+    }
+
+    if let Some(sub_m) = matches.subcommand_matches("evaluate") {
+        hmm::evaluate_callback(&sub_m)?
+    }
+
     if let Some(sub_m) = matches.subcommand_matches("transform") {
         transform::callback(&sub_m)?
     }
 
+    if let Some(sub_m) = matches.subcommand_matches("subset") {
+        subset::callback(&sub_m)?
+    }
+
+    if let Some(sub_m) = matches.subcommand_matches("merge") {
+        merge::callback(&sub_m)?
+    }
+
+    if let Some(sub_m) = matches.subcommand_matches("anchors") {
+        anchor::callback(&sub_m)?
+    }
+
     Ok(())
 }
@@ -0,0 +1,353 @@
+use std::error::Error;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{Instant, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::anchor::AnchorWeighting;
+use crate::config::{ProbT, VALID_STATES, WINDOW_SIZE};
+use crate::model::Hmm;
+
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// provenance of an input file, its CRC32 checksum only if asked for
+/// since it reads the whole file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FileRecord {
+    pub path: PathBuf,
+    pub size: u64,
+    /// modification time, in seconds since the epoch.
+    #[serde(default)]
+    pub mtime: Option<u64>,
+    #[serde(default)]
+    pub crc32: Option<u32>,
+}
+
+impl FileRecord {
+    /// size and modification time of a file, or of all the files of a
+    /// directory in the order of their names, along with their checksum
+    /// if `checksum` is set.
+    pub fn from_path(path: &Path, checksum: bool) -> Result<FileRecord, Box<dyn Error>> {
+        let file_paths: Vec<PathBuf> = match path.is_dir() {
+            true => {
+                let mut file_paths: Vec<PathBuf> = std::fs::read_dir(path)?
+                    .filter_map(|entry| entry.ok())
+                    .map(|entry| entry.path())
+                    .filter(|x| x.is_file())
+                    .collect();
+                file_paths.sort();
+                file_paths
+            }
+            false => vec![path.to_path_buf()],
+        };
+
+        let (mut size, mut mtime) = (0, 0);
+        for file_path in &file_paths {
+            let metadata = std::fs::metadata(file_path)
+                .map_err(|e| format!("can't read {:?}: {}", file_path, e))?;
+            size += metadata.len();
+            let modified = metadata.modified()?.duration_since(UNIX_EPOCH)?;
+            mtime = mtime.max(modified.as_secs());
+        }
+
+        let crc32 = match checksum {
+            true => Some(get_crc32(&file_paths)?),
+            false => None,
+        };
+
+        Ok(FileRecord {
+            path: std::fs::canonicalize(path)?,
+            size,
+            mtime: Some(mtime),
+            crc32,
+        })
+    }
+
+    /// same content by checksum if both have one, else by size and
+    /// modification time.
+    pub fn matches(&self, other: &FileRecord) -> bool {
+        if self.size != other.size {
+            return false;
+        }
+
+        match (self.crc32, other.crc32) {
+            (Some(x), Some(y)) => x == y,
+            _ => self.mtime == other.mtime,
+        }
+    }
+}
+
+fn get_crc32(file_paths: &[PathBuf]) -> Result<u32, Box<dyn Error>> {
+    let mut crc = flate2::Crc::new();
+    let mut buffer = vec![0 as u8; 1 << 20];
+    for file_path in file_paths {
+        let mut file_handle = std::fs::File::open(file_path)?;
+        loop {
+            let num_bytes = file_handle.read(&mut buffer)?;
+            if num_bytes == 0 {
+                break;
+            }
+            crc.update(&buffer[..num_bytes]);
+        }
+    }
+
+    Ok(crc.sum())
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChromosomeRecord {
+    pub name: String,
+    pub num_bins: usize,
+    pub num_cells: usize,
+    pub seconds: f64,
+}
+
+/// one invocation of a subcommand on the output directory.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RunRecord {
+    pub command: String,
+    pub version: String,
+    pub arguments: Vec<String>,
+    pub chromosomes: Vec<ChromosomeRecord>,
+}
+
+impl RunRecord {
+    pub fn new(command: &str) -> RunRecord {
+        RunRecord {
+            command: command.to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            arguments: std::env::args().collect(),
+            chromosomes: Vec::new(),
+        }
+    }
+
+    pub fn add_chromosome(
+        &mut self,
+        name: &str,
+        num_bins: usize,
+        num_cells: usize,
+        start: Instant,
+    ) {
+        self.chromosomes.push(ChromosomeRecord {
+            name: name.to_string(),
+            num_bins,
+            num_cells,
+            seconds: start.elapsed().as_secs_f64(),
+        });
+    }
+}
+
+////////////////////////////////////////////
+/// Manifest
+/// how an output directory was produced, written by every subcommand
+/// as `manifest.json` and validated by the subcommands consuming it.
+////////////////////////////////////////////
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Manifest {
+    /// "long" for the hmm and predict output, "short" for the transform
+    /// output and "imputed" for the impute output.
+    pub format: String,
+    /// absent for `impute` runs without a model.
+    pub model: Option<FileRecord>,
+    pub num_states: usize,
+    pub num_assays: usize,
+    pub thresholds: Vec<ProbT>,
+    /// how the anchor scores were turned into weights.
+    #[serde(default)]
+    pub anchor_weighting: AnchorWeighting,
+    /// depth normalisations applied before the thresholds.
+    #[serde(default)]
+    pub depth_norm: Vec<String>,
+    /// p-value of the per cell Poisson thresholds, replacing the fixed ones.
+    #[serde(default)]
+    pub threshold_pvalue: Option<f64>,
+    pub window_size: usize,
+    /// 1-indexed states present in the output.
+    pub states: Vec<usize>,
+    /// marks of the `predict` output, taking the place of the states.
+    #[serde(default)]
+    pub marks: Vec<String>,
+    /// marks of the model marginalised out for lack of an assay.
+    #[serde(default)]
+    pub unmeasured_marks: Vec<String>,
+    pub fragments: Vec<FileRecord>,
+    pub anchors: Vec<FileRecord>,
+    pub common_cells: FileRecord,
+    pub shard: Option<(usize, usize)>,
+    pub runs: Vec<RunRecord>,
+}
+
+impl Manifest {
+    /// `model` is the model with its path, absent for `impute` without one.
+    pub fn new(
+        model: Option<(&Hmm, &Path)>,
+        num_assays: usize,
+        fragment_paths: &[PathBuf],
+        anchor_paths: &[PathBuf],
+        common_cells_path: &Path,
+        shard: Option<(usize, usize)>,
+        checksum: bool,
+    ) -> Result<Manifest, Box<dyn Error>> {
+        let hmm = model.map(|x| x.0);
+        if checksum {
+            info!("Computing input checksums for the manifest");
+        }
+        let fragments = fragment_paths
+            .iter()
+            .map(|x| FileRecord::from_path(x, checksum))
+            .collect::<Result<_, _>>()?;
+        let anchors = anchor_paths
+            .iter()
+            .map(|x| FileRecord::from_path(x, checksum))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Manifest {
+            format: "long".to_string(),
+            // the model is small, and compared across runs on other machines.
+            model: match model {
+                Some((_, path)) => Some(FileRecord::from_path(path, true)?),
+                None => None,
+            },
+            num_states: hmm.map_or(0, |x| x.num_states()),
+            num_assays,
+            thresholds: hmm.map_or(Vec::new(), |x| x.get_thresholds()),
+            anchor_weighting: AnchorWeighting::default(),
+            depth_norm: Vec::new(),
+            threshold_pvalue: None,
+            window_size: WINDOW_SIZE,
+            states: VALID_STATES
+                .iter()
+                .filter(|&&x| x < hmm.map_or(0, |x| x.num_states()))
+                .map(|x| x + 1)
+                .collect(),
+            marks: Vec::new(),
+            unmeasured_marks: hmm.map_or(Vec::new(), |x| x.unmeasured_marks()),
+            fragments,
+            anchors,
+            common_cells: FileRecord::from_path(common_cells_path, checksum)?,
+            shard,
+            runs: Vec::new(),
+        })
+    }
+
+    /// manifest of an output directory, `None` for outputs predating it.
+    pub fn from_dir(path: &Path) -> Result<Option<Manifest>, Box<dyn Error>> {
+        let manifest_path = path.join(MANIFEST_FILE);
+        if !manifest_path.exists() {
+            return Ok(None);
+        }
+
+        let reader = carina::file::bufreader_from_filepath(manifest_path.clone())?;
+        let manifest = serde_json::from_reader(reader)
+            .map_err(|e| format!("can't parse {:?}: {}", manifest_path, e))?;
+        Ok(Some(manifest))
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        std::fs::create_dir_all(path)?;
+        let bytes = serde_json::to_vec_pretty(self)?;
+        crate::file::write_binary(path.join(MANIFEST_FILE), bytes)
+    }
+
+    pub fn expect_format(&self, format: &str, path: &Path) -> Result<(), Box<dyn Error>> {
+        if self.format != format {
+            return Err(format!(
+                "expected {} format in {:?}, found {}",
+                format, path, self.format
+            )
+            .into());
+        }
+
+        Ok(())
+    }
+
+    /// number of bins of a chromosome recorded by the earlier runs.
+    pub fn num_bins(&self, chr_name: &str) -> usize {
+        self.runs
+            .iter()
+            .flat_map(|x| x.chromosomes.iter())
+            .find(|x| x.name == chr_name)
+            .map_or(0, |x| x.num_bins)
+    }
+
+    /// errors if the two outputs can't be decoded with the same model.
+    pub fn check_model(&self, other: &Manifest) -> Result<(), Box<dyn Error>> {
+        let mismatch = |field: &str| -> Result<(), Box<dyn Error>> {
+            Err(format!(
+                "mismatched {} between the runs with models {:?} and {:?}",
+                field,
+                self.model.as_ref().map(|x| &x.path),
+                other.model.as_ref().map(|x| &x.path)
+            )
+            .into())
+        };
+
+        if self.format != other.format {
+            return mismatch("format");
+        }
+        let same_model = match (&self.model, &other.model) {
+            (Some(x), Some(y)) => x.matches(y),
+            (x, y) => x == y,
+        };
+        if !same_model {
+            return mismatch("model");
+        }
+        if self.num_states != other.num_states || self.num_assays != other.num_assays {
+            return mismatch("number of states or assays");
+        }
+        if self.thresholds != other.thresholds {
+            return mismatch("thresholds");
+        }
+        if self.anchor_weighting != other.anchor_weighting {
+            return mismatch("anchor weighting");
+        }
+        if self.depth_norm != other.depth_norm {
+            return mismatch("depth normalisation");
+        }
+        if self.threshold_pvalue != other.threshold_pvalue {
+            return mismatch("threshold mode");
+        }
+        if self.window_size != other.window_size {
+            return mismatch("bin size");
+        }
+        if self.states != other.states {
+            return mismatch("states");
+        }
+        if self.marks != other.marks {
+            return mismatch("marks");
+        }
+        if self.unmeasured_marks != other.unmeasured_marks {
+            return mismatch("unmeasured marks");
+        }
+
+        Ok(())
+    }
+
+    /// errors if the two runs didn't use the same model and input data.
+    pub fn check_inputs(&self, other: &Manifest) -> Result<(), Box<dyn Error>> {
+        self.check_model(other)?;
+
+        let matches = |x: &[FileRecord], y: &[FileRecord]| {
+            x.len() == y.len() && x.iter().zip(y.iter()).all(|(x, y)| x.matches(y))
+        };
+        if !matches(&self.fragments, &other.fragments) {
+            return Err("mismatched fragment files with the earlier run".into());
+        }
+        if !matches(&self.anchors, &other.anchors) {
+            return Err("mismatched anchor files with the earlier run".into());
+        }
+        if !self.common_cells.matches(&other.common_cells) {
+            return Err("mismatched common cells with the earlier run".into());
+        }
+        if self.shard != other.shard {
+            return Err(format!(
+                "mismatched shard with the earlier run, {:?} vs {:?}",
+                self.shard, other.shard
+            )
+            .into());
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,271 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::BufRead;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use crate::barcode::BarcodeTable;
+use crate::config::{ProbT, RangeT, WINDOW_SIZE};
+use crate::file;
+use crate::record::Record;
+
+/// first existing `<dir>/<name>[.gz]` out of the names.
+fn find_file(dir: &Path, names: &[&str]) -> Result<PathBuf, Box<dyn Error>> {
+    for name in names {
+        for file_name in &[name.to_string(), format!("{}.gz", name)] {
+            let path = dir.join(file_name);
+            if path.exists() {
+                return Ok(path);
+            }
+        }
+    }
+
+    Err(format!("can't find any of {:?} in {:?}", names, dir).into())
+}
+
+/// (chromosome, 0-indexed bin) of a bin named `chr:start-end`, `chr-start-end`,
+/// `chr_start_end` or given as tab separated BED columns.
+fn parse_bin(line: &str) -> Result<(String, usize), Box<dyn Error>> {
+    let toks: Vec<&str> = line.split('\t').collect();
+    let (chr, start, end) = match toks.len() >= 3 && toks[1].parse::<u64>().is_ok() {
+        true => (toks[0], toks[1], toks[2]),
+        false => {
+            let name = toks[0];
+            let mut parts = name.rsplitn(3, |c| c == ':' || c == '-' || c == '_');
+            let end = parts.next().ok_or("missing bin end")?;
+            let start = parts.next().ok_or("missing bin start")?;
+            let chr = parts.next().ok_or("missing bin chromosome")?;
+            (chr, start, end)
+        }
+    };
+    let (start, end) = (start.parse::<usize>()?, end.parse::<usize>()?);
+
+    // bins can be 0-indexed half open or 1-indexed closed intervals.
+    let length = end.saturating_sub(start);
+    let is_valid = (start % WINDOW_SIZE == 0 && length == WINDOW_SIZE)
+        || (start % WINDOW_SIZE == 1 && length + 1 == WINDOW_SIZE)
+        || (start % WINDOW_SIZE == 0 && length + 1 == WINDOW_SIZE);
+    if !is_valid {
+        return Err(format!(
+            "bin {}:{}-{} doesn't match the {}bp bin size",
+            chr, start, end, WINDOW_SIZE
+        )
+        .into());
+    }
+
+    Ok((chr.to_string(), start / WINDOW_SIZE))
+}
+
+/// 0-indexed (row, column, value) of a MatrixMarket entry, checked
+/// against the dimensions of the matrix.
+fn parse_entry(
+    line: &str,
+    num_rows: usize,
+    num_cols: usize,
+) -> Result<(usize, usize, ProbT), String> {
+    let toks: Vec<&str> = line.split_whitespace().collect();
+    if toks.len() < 3 {
+        return Err(format!("malformed entry {}", line));
+    }
+
+    let get_index = |tok: &str, size: usize| match tok.parse::<usize>() {
+        Ok(index) if index >= 1 && index <= size => Ok(index - 1),
+        _ => Err(format!("index {} not in [1, {}]", tok, size)),
+    };
+    let (row, col) = (get_index(toks[0], num_rows)?, get_index(toks[1], num_cols)?);
+    let value = toks[2]
+        .parse::<ProbT>()
+        .map_err(|_| format!("can't parse value {}", toks[2]))?;
+
+    Ok((row, col, value))
+}
+
+////////////////////////////////////////////
+/// Count Matrix
+/// a bin by cell count matrix in a directory with `matrix.mtx`,
+/// `barcodes.tsv` and `bins.tsv` (or `features.tsv`/`peaks.bed`),
+/// optionally gzipped.
+////////////////////////////////////////////
+pub struct CountMatrix {
+    barcodes: Vec<String>,
+    chr_names: Vec<String>,
+    // per chromosome (bin, barcode column, count) entries.
+    entries: Vec<Vec<(u32, u32, ProbT)>>,
+}
+
+impl CountMatrix {
+    pub fn from_dir(dir: &Path) -> Result<CountMatrix, Box<dyn Error>> {
+        let barcodes_path = find_file(dir, &["barcodes.tsv"])?;
+        let barcodes: Vec<String> = file::bufreader_from_maybe_gz(&barcodes_path)?
+            .lines()
+            .map(|x| x.map(|x| x.split('\t').next().unwrap().to_string()))
+            .collect::<Result<_, _>>()?;
+
+        let mut chr_names: Vec<String> = Vec::new();
+        let mut chr_ids: HashMap<String, usize> = HashMap::new();
+        let mut bins: Vec<(usize, u32)> = Vec::new();
+        let bins_path = find_file(dir, &["bins.tsv", "features.tsv", "peaks.bed"])?;
+        for (line_num, line) in file::bufreader_from_maybe_gz(&bins_path)?
+            .lines()
+            .enumerate()
+        {
+            let (chr, bin) = parse_bin(&line?)
+                .map_err(|e| format!("line {} of {:?}: {}", line_num + 1, bins_path, e))?;
+            let chr_id = *chr_ids.entry(chr.clone()).or_insert_with(|| {
+                chr_names.push(chr);
+                chr_names.len() - 1
+            });
+            bins.push((chr_id, bin as u32));
+        }
+
+        let matrix_path = find_file(dir, &["matrix.mtx"])?;
+        let mut reader = file::bufreader_from_maybe_gz(&matrix_path)?.lines();
+        let (mut header, mut num_header_lines) = (String::new(), 0);
+        for line in &mut reader {
+            let line = line?;
+            num_header_lines += 1;
+            if !line.starts_with('%') {
+                header = line;
+                break;
+            }
+        }
+        let dims: Vec<usize> = header
+            .split_whitespace()
+            .map(|x| x.parse::<usize>())
+            .collect::<Result<_, _>>()
+            .map_err(|_| format!("can't parse the dimensions of {:?}", matrix_path))?;
+        if dims.len() != 3 {
+            return Err(format!("can't parse the dimensions of {:?}", matrix_path).into());
+        }
+
+        // bins are expected as rows, but cell by bin matrices are accepted too.
+        let is_transposed = match (dims[0], dims[1]) {
+            (r, c) if r == bins.len() && c == barcodes.len() => false,
+            (r, c) if r == barcodes.len() && c == bins.len() => true,
+            _ => {
+                return Err(format!(
+                    "{:?} is {}x{}, expected {} bins by {} barcodes",
+                    matrix_path,
+                    dims[0],
+                    dims[1],
+                    bins.len(),
+                    barcodes.len()
+                )
+                .into())
+            }
+        };
+
+        let mut entries = vec![Vec::new(); chr_names.len()];
+        for (line_num, line) in reader.enumerate() {
+            let (row, col, count) = parse_entry(&line?, dims[0], dims[1]).map_err(|e| {
+                format!(
+                    "{:?} line {}: {}",
+                    matrix_path,
+                    num_header_lines + line_num + 1,
+                    e
+                )
+            })?;
+            let (bin_id, barcode_id) = match is_transposed {
+                true => (col, row),
+                false => (row, col),
+            };
+
+            let (chr_id, bin) = bins[bin_id];
+            entries[chr_id].push((bin, barcode_id as u32, count));
+        }
+        entries
+            .iter_mut()
+            .for_each(|x| x.sort_unstable_by_key(|x| x.0));
+
+        Ok(CountMatrix {
+            barcodes,
+            chr_names,
+            entries,
+        })
+    }
+
+    pub fn tid(&self, seqname: &str) -> Option<u64> {
+        self.chr_names
+            .iter()
+            .position(|x| x == seqname)
+            .map(|x| x as u64)
+    }
+
+    /// anchored entries of the region as 1bp records at the bin midpoint,
+    /// weighted by the count.
+    pub fn fetch(
+        &self,
+        tid: u64,
+        region: &Range<u32>,
+        barcodes: &BarcodeTable,
+        library_suffix: Option<&str>,
+    ) -> Vec<(Record<u32>, ProbT)> {
+        let ids: Vec<Option<u32>> = self
+            .barcodes
+            .iter()
+            .map(|x| barcodes.get(x, library_suffix))
+            .collect();
+
+        self.entries[tid as usize]
+            .iter()
+            .filter_map(|&(bin, barcode_id, count)| {
+                let mid = (bin as usize * WINDOW_SIZE + WINDOW_SIZE / 2) as RangeT;
+                if mid < region.start || mid >= region.end {
+                    return None;
+                }
+
+                ids[barcode_id as usize].map(|id| (Record::new_with_id(&(mid..mid + 1), id), count))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_bin() {
+        for line in &[
+            "chr1:400-600",
+            "chr1-401-600",
+            "chr1_400_599",
+            "chr1\t400\t600\tpeak",
+        ] {
+            assert_eq!(parse_bin(line).unwrap(), ("chr1".to_string(), 2));
+        }
+        assert!(parse_bin("chr1:400-800").is_err());
+        assert!(parse_bin("chr1:450-650").is_err());
+        assert!(parse_bin("chr1").is_err());
+    }
+
+    #[test]
+    fn test_parse_entry() {
+        assert_eq!(parse_entry("2 3 1.5", 2, 3).unwrap(), (1, 2, 1.5));
+        assert!(parse_entry("0 1 1", 2, 3).is_err());
+        assert!(parse_entry("3 1 1", 2, 3).is_err());
+        assert!(parse_entry("1 4 1", 2, 3).is_err());
+        assert!(parse_entry("1 1", 2, 3).is_err());
+        assert!(parse_entry("1 1 x", 2, 3).is_err());
+    }
+
+    #[test]
+    fn test_from_dir_bad_index() {
+        let dir = std::env::temp_dir().join("schrom_test_matrix_bad_index");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("barcodes.tsv"), "AAA-1\nCCC-1\n").unwrap();
+        std::fs::write(dir.join("bins.tsv"), "chr1:0-200\nchr1:200-400\n").unwrap();
+        let header = "%%MatrixMarket matrix coordinate real general\n2 2 2\n";
+        std::fs::write(dir.join("matrix.mtx"), format!("{}1 1 1\n2 2 3\n", header)).unwrap();
+        let matrix = CountMatrix::from_dir(&dir).unwrap();
+        assert_eq!(matrix.entries, [vec![(0, 0, 1.0), (1, 1, 3.0)]]);
+
+        std::fs::write(dir.join("matrix.mtx"), format!("{}1 1 1\n0 2 3\n", header)).unwrap();
+        let err = CountMatrix::from_dir(&dir).err().unwrap().to_string();
+        assert!(err.contains("line 4"), "{}", err);
+
+        std::fs::write(dir.join("matrix.mtx"), format!("{}3 1 1\n", header)).unwrap();
+        assert!(CountMatrix::from_dir(&dir).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
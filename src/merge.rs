@@ -0,0 +1,231 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use clap::ArgMatches;
+
+use crate::hmm;
+use crate::manifest::{Manifest, RunRecord, MANIFEST_FILE};
+use crate::posterior::{self, StatePosterior};
+
+fn merge_chromosome(
+    in_paths: &[PathBuf],
+    out_path: &Path,
+    chr_name: &str,
+) -> Result<usize, Box<dyn Error>> {
+    let chr_paths: Vec<PathBuf> = in_paths.iter().map(|x| x.join(chr_name)).collect();
+
+    // cells have to be disjoint across the runs.
+    let mut seen_cells: HashSet<String> = HashSet::new();
+    let mut run_cells: Vec<Vec<String>> = Vec::with_capacity(chr_paths.len());
+    for chr_path in &chr_paths {
+        let cells = posterior::read_cells(chr_path.join("cells.txt"))?;
+        for cell in &cells {
+            if !seen_cells.insert(cell.clone()) {
+                return Err(format!(
+                    "cell {} of {:?} is present in more than one run",
+                    cell, chr_path
+                )
+                .into());
+            }
+        }
+        run_cells.push(cells);
+    }
+
+    // all the runs have to be from the same model.
+    let state_files = posterior::get_state_files(&chr_paths[0])?;
+    for chr_path in chr_paths.iter().skip(1) {
+        if posterior::get_state_files(chr_path)? != state_files {
+            return Err(format!(
+                "{:?} and {:?} have different states, runs are not from the same model",
+                chr_paths[0], chr_path
+            )
+            .into());
+        }
+    }
+
+    let mut cell_id_handle =
+        std::io::BufWriter::new(std::fs::File::create(out_path.join("cells.txt"))?);
+    for cell in run_cells.iter().flatten() {
+        writeln!(cell_id_handle, "{}", cell)?;
+    }
+
+    for state in state_files {
+        let file_name = format!("{}.bin", state);
+        let mut out_mat: Option<StatePosterior> = None;
+        for (chr_path, cells) in chr_paths.iter().zip(run_cells.iter()) {
+            let in_mat = StatePosterior::from_path(&chr_path.join(&file_name))?;
+            if let Some(in_mat) = &in_mat {
+                if in_mat.num_cells() != cells.len() {
+                    return Err(format!(
+                        "{:?} has {} cells, cells.txt has {}",
+                        chr_path.join(&file_name),
+                        in_mat.num_cells(),
+                        cells.len()
+                    )
+                    .into());
+                }
+
+                match &out_mat {
+                    Some(out_mat) if out_mat.num_bins() != in_mat.num_bins() => {
+                        return Err(format!(
+                            "{:?} has {} bins, expected {}; runs used different bin size or chromosome lengths",
+                            chr_path.join(&file_name),
+                            in_mat.num_bins(),
+                            out_mat.num_bins()
+                        )
+                        .into());
+                    }
+                    Some(_) => (),
+                    None => out_mat = Some(StatePosterior::new(in_mat.num_bins())),
+                };
+            }
+        }
+
+        // no run has entries for this state.
+        let mut out_mat = match out_mat {
+            Some(out_mat) => out_mat,
+            None => {
+                std::fs::File::create(out_path.join(&file_name))?;
+                continue;
+            }
+        };
+
+        for (chr_path, cells) in chr_paths.iter().zip(run_cells.iter()) {
+            match StatePosterior::from_path(&chr_path.join(&file_name))? {
+                Some(in_mat) => (0..in_mat.num_cells()).for_each(|cell_id| {
+                    let (probs, indices) = in_mat.get_cell(cell_id);
+                    out_mat.push_cell(probs, indices);
+                }),
+                None => cells.iter().for_each(|_| out_mat.push_cell(&[], &[])),
+            }
+        }
+        out_mat.write(out_path.join(&file_name))?;
+    }
+
+    Ok(seen_cells.len())
+}
+
+/// sharded runs have to be distinct shards of the same split.
+fn check_shards(in_paths: &[PathBuf]) -> Result<(), Box<dyn Error>> {
+    let mut shards: Vec<(usize, usize)> = Vec::new();
+    for in_path in in_paths {
+        if let Some(shard) = posterior::read_shard(in_path)? {
+            shards.push(shard);
+        }
+    }
+    if shards.is_empty() {
+        return Ok(());
+    }
+
+    let num_shards = shards[0].1;
+    if shards.iter().any(|x| x.1 != num_shards) {
+        return Err(format!("runs are from different splits of the cells: {:?}", shards).into());
+    }
+
+    let indices: HashSet<usize> = shards.iter().map(|x| x.0).collect();
+    if indices.len() != shards.len() {
+        return Err(format!("same shard is present more than once: {:?}", shards).into());
+    }
+
+    if shards.len() != in_paths.len() || indices.len() != num_shards {
+        warn!(
+            "Merging {} of the {} shards, the merged output doesn't have all the cells",
+            indices.len(),
+            num_shards
+        );
+    }
+
+    Ok(())
+}
+
+/// all the runs have to be transform outputs of the same model.
+fn check_manifests(in_paths: &[PathBuf]) -> Result<Option<Manifest>, Box<dyn Error>> {
+    let mut manifests = Vec::with_capacity(in_paths.len());
+    for in_path in in_paths {
+        match Manifest::from_dir(in_path)? {
+            Some(manifest) => {
+                manifest.expect_format("short", in_path)?;
+                manifests.push(manifest);
+            }
+            None => {
+                warn!(
+                    "Can't find {} in {:?}, can't validate the runs are from the same model",
+                    MANIFEST_FILE, in_path
+                );
+                return Ok(None);
+            }
+        }
+    }
+
+    for manifest in manifests.iter().skip(1) {
+        manifests[0].check_model(manifest)?;
+    }
+
+    let mut manifest = manifests.swap_remove(0);
+    manifest.shard = None;
+    manifest.runs.push(RunRecord::new("merge"));
+    Ok(Some(manifest))
+}
+
+pub fn callback(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let in_paths = carina::file::files_path_from_clap(&sub_m, "in_directories")?;
+    info!("Found {} input directories: {:?}", in_paths.len(), in_paths);
+
+    let out_path = PathBuf::from(sub_m.value_of("out_directory").unwrap());
+    info!("Found output directory path: {:?}", out_path);
+
+    check_shards(&in_paths)?;
+    let mut manifest = check_manifests(&in_paths)?;
+
+    let chrs = posterior::get_chromosomes(&in_paths[0])?;
+    for in_path in in_paths.iter().skip(1) {
+        if posterior::get_chromosomes(in_path)? != chrs {
+            return Err(format!(
+                "{:?} and {:?} have different chromosomes",
+                in_paths[0], in_path
+            )
+            .into());
+        }
+    }
+    info!("Found total {} chromosomes", chrs.len());
+
+    let pbar = hmm::get_progress_bar(chrs.len());
+    for chr_name in chrs {
+        for in_path in &in_paths {
+            if !in_path.join(&chr_name).join("cells.txt").exists() {
+                return Err(format!(
+                    "{:?} is not a transform output directory, can't find {}/cells.txt",
+                    in_path, chr_name
+                )
+                .into());
+            }
+        }
+
+        let chr_out_path = out_path.join(&chr_name);
+        std::fs::create_dir_all(&chr_out_path)?;
+
+        let chr_start = Instant::now();
+        let num_cells = merge_chromosome(&in_paths, &chr_out_path, &chr_name)?;
+        if let Some(manifest) = manifest.as_mut() {
+            let num_bins = manifest.num_bins(&chr_name);
+            manifest
+                .runs
+                .last_mut()
+                .unwrap()
+                .add_chromosome(&chr_name, num_bins, num_cells, chr_start);
+        }
+        pbar.set_message(&format!("{} with {} cells", chr_name, num_cells));
+        pbar.inc(1);
+    }
+    pbar.finish();
+
+    if let Some(manifest) = manifest {
+        manifest.write(&out_path)?;
+    }
+
+    info!("All Done");
+    Ok(())
+}
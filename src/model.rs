@@ -1,15 +1,20 @@
-use crate::config::ProbT;
-use crate::config::THRESHOLDS;
+use crate::config::{ProbT, MISSING_CODE, THRESHOLDS};
 use clap::ArgMatches;
 use std::error::Error;
 use std::fmt;
 use std::io::BufRead;
 
+#[derive(Clone)]
 pub struct Hmm {
     init: Vec<ProbT>,
+    /// emission probabilities per observation code, per state, followed
+    /// by the ones of `MISSING_CODE`.
     emission: Vec<Vec<ProbT>>,
+    presence: Vec<Vec<ProbT>>,
+    marks: Vec<String>,
     transition: Vec<Vec<ProbT>>,
-    num_assays: usize,
+    /// model indices of the assays in the observations.
+    assays: Vec<usize>,
 }
 
 impl fmt::Debug for Hmm {
@@ -17,6 +22,7 @@ impl fmt::Debug for Hmm {
         f.debug_struct("Found ")
             .field("#states", &self.num_states())
             .field("#assays", &self.num_assays())
+            .field("#marks", &self.num_marks())
             //.field("initialization proabilities", &self.transition)
             .finish()
     }
@@ -27,27 +33,86 @@ impl Hmm {
         self.init[state]
     }
 
-    pub fn get_transition_prob(&self, pstate: usize, state: usize) -> ProbT {
-        self.transition[pstate][state]
+    /// transition probabilities out of a state, to every state.
+    pub fn get_transitions(&self, pstate: usize) -> &[ProbT] {
+        &self.transition[pstate]
     }
 
-    pub fn get_emission_prob(&self, state: usize, observations: &[ProbT]) -> ProbT {
-        let mut id: usize = 0;
-        for (index, &observation) in observations.iter().enumerate() {
-            if observation > THRESHOLDS[index] {
-                id |= 1 << index;
-            }
+    /// emission probabilities of every state for an observation code,
+    /// see `quantify::get_observation_code`.
+    pub fn get_emissions(&self, code: u32) -> &[ProbT] {
+        match code {
+            MISSING_CODE => self.emission.last().unwrap(),
+            _ => &self.emission[code as usize],
         }
+    }
+
+    /// probability of a mark being present in a state.
+    pub fn get_presence_prob(&self, state: usize, mark: usize) -> ProbT {
+        self.presence[state][mark]
+    }
+
+    pub fn mark_names(&self) -> &[String] {
+        &self.marks
+    }
 
-        self.emission[state][id]
+    /// names of the marks without an assay in the observations.
+    pub fn unmeasured_marks(&self) -> Vec<String> {
+        (0..self.num_marks())
+            .filter(|x| !self.assays.contains(x))
+            .map(|x| self.marks[x].clone())
+            .collect()
     }
 
     pub fn num_states(&self) -> usize {
         self.init.len()
     }
 
+    /// number of assays in the observations, excluding the unmeasured marks.
     pub fn num_assays(&self) -> usize {
-        self.num_assays
+        self.assays.len()
+    }
+
+    /// fixed binarisation thresholds of the assays in the observations.
+    pub fn get_thresholds(&self) -> Vec<ProbT> {
+        self.assays.iter().map(|&x| THRESHOLDS[x]).collect()
+    }
+
+    /// model indices of the assays in the observations.
+    pub fn assays(&self) -> &[usize] {
+        &self.assays
+    }
+
+    pub fn num_marks(&self) -> usize {
+        self.marks.len()
+    }
+
+    /// the model with the mark of the given assay marginalised out as well.
+    pub fn without_assay(&self, assay: usize) -> Hmm {
+        let mut hmm = self.clone();
+        hmm.assays.remove(assay);
+        hmm.emission = get_all_emission(&hmm.presence, &hmm.assays);
+        hmm
+    }
+
+    /// marginalises the given marks out of the emission probabilities, so
+    /// that the observations only have the remaining assays, in model order.
+    pub fn drop_marks(&mut self, names: &[&str]) -> Result<(), Box<dyn Error>> {
+        for name in names {
+            if !self.marks.iter().any(|x| x == name) {
+                return Err(format!("can't find mark {} in the model", name).into());
+            }
+        }
+
+        self.assays = (0..self.num_marks())
+            .filter(|&x| !names.contains(&self.marks[x].as_str()))
+            .collect();
+        if self.assays.is_empty() {
+            return Err("can't drop all the marks of the model".into());
+        }
+        self.emission = get_all_emission(&self.presence, &self.assays);
+
+        Ok(())
     }
 
     pub fn new(mut reader: std::io::BufReader<std::fs::File>) -> Hmm {
@@ -62,6 +127,7 @@ impl Hmm {
 
         let mut init = vec![0.0; num_states];
         let mut emission = vec![vec![0.0; num_assays]; num_states];
+        let mut marks = vec![String::new(); num_assays];
         let mut transition = vec![vec![0.0; num_states]; num_states];
 
         let (mut pcounter, mut tcounter, mut ecounter) = (0, 0, 0);
@@ -96,6 +162,7 @@ impl Hmm {
                     let assay = toks[2].parse::<usize>().unwrap();
                     let probability = toks[5].parse::<ProbT>().unwrap();
                     emission[state][assay] = probability;
+                    marks[assay] = toks[3].to_string();
                     ecounter += 1;
                 }
                 _ => unreachable!(),
@@ -105,39 +172,57 @@ impl Hmm {
         assert_eq!(tcounter, num_states * num_states);
         assert_eq!(ecounter, num_states * num_assays);
 
-        let num_all_combinations = 2_usize.pow(num_assays as u32);
-        let mut all_emission = vec![vec![1.0; num_all_combinations]; num_states];
-        for state in 0..num_states {
-            for i in 0..num_all_combinations {
-                let mut flags = vec![false; num_assays];
-                for (index, &is_present) in format!("{:b}", i).as_bytes().iter().rev().enumerate() {
-                    if is_present == 49 {
-                        flags[index] = true;
-                    }
-                }
-
-                for (index, is_present) in flags.into_iter().enumerate() {
-                    match is_present {
-                        true => all_emission[state][i] *= emission[state][index],
-                        false => all_emission[state][i] *= 1.0 - emission[state][index],
-                    }
-                }
-            }
-        }
+        let assays: Vec<usize> = (0..num_assays).collect();
+        let all_emission = get_all_emission(&emission, &assays);
 
         Hmm {
             init,
             emission: all_emission,
+            presence: emission,
+            marks,
             transition,
-            num_assays,
+            assays,
         }
     }
 }
 
+/// emission probabilities of every state for every presence combination
+/// of the given assays, the i-th assay being present if the i-th bit is set,
+/// and of the missing observation last.
+fn get_all_emission(presence: &[Vec<ProbT>], assays: &[usize]) -> Vec<Vec<ProbT>> {
+    let num_states = presence.len();
+    let num_assays = assays.len();
+    let num_all_combinations = 2_usize.pow(num_assays as u32);
+    let mut all_emission = vec![vec![1.0; num_states]; num_all_combinations + 1];
+    for state in 0..num_states {
+        for i in 0..num_all_combinations {
+            let mut flags = vec![false; num_assays];
+            for (index, &is_present) in format!("{:b}", i).as_bytes().iter().rev().enumerate() {
+                if is_present == 49 {
+                    flags[index] = true;
+                }
+            }
+
+            for (index, is_present) in flags.into_iter().enumerate() {
+                let emission = presence[state][assays[index]];
+                match is_present {
+                    true => all_emission[i][state] *= emission,
+                    false => all_emission[i][state] *= 1.0 - emission,
+                }
+            }
+        }
+    }
+
+    all_emission
+}
+
 pub fn get_hmm_params(sub_m: &ArgMatches) -> Result<Hmm, Box<dyn Error>> {
     let hmm_file_path = carina::file::file_path_from_clap(sub_m, "model")?;
     let file_reader = carina::file::bufreader_from_filepath(hmm_file_path)?;
-    let hmm = Hmm::new(file_reader);
+    let mut hmm = Hmm::new(file_reader);
+    if let Some(names) = sub_m.values_of("unmeasured_marks") {
+        hmm.drop_marks(&names.collect::<Vec<&str>>())?;
+    }
 
     Ok(hmm)
 }
@@ -0,0 +1,309 @@
+use std::convert::TryInto;
+use std::error::Error;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// written by sharded `hmm` runs, of the form `i/n`.
+pub const SHARD_FILE: &str = "shard.txt";
+
+/// written by `hmm` in a chromosome directory once all its cells are done.
+pub const DONE_FILE: &str = ".done";
+
+////////////////////////////////////////////
+/// Cell Posterior
+/// "long" format written by `hmm`, one file per cell:
+/// nnz, num_bins, num_states (u32) followed by
+/// nnz probs (u8), nnz states (u8), nnz bin indices (u32).
+////////////////////////////////////////////
+#[derive(Debug)]
+pub struct CellPosterior {
+    num_bins: u32,
+    num_states: u32,
+    probs: Vec<u8>,
+    states: Vec<u8>,
+    indices: Vec<u32>,
+}
+
+impl CellPosterior {
+    pub fn from_triplets(
+        triplets: &[(usize, usize, f32)],
+        num_bins: usize,
+        num_states: usize,
+    ) -> CellPosterior {
+        CellPosterior {
+            num_bins: num_bins as u32,
+            num_states: num_states as u32,
+            probs: triplets
+                .iter()
+                .map(|x| (x.2 * 100.0).round() as u8)
+                .collect(),
+            states: triplets.iter().map(|x| x.1 as u8).collect(),
+            indices: triplets.iter().map(|x| x.0 as u32).collect(),
+        }
+    }
+
+    pub fn from_path(path: &Path) -> Result<CellPosterior, Box<dyn Error>> {
+        let mut file_handle = carina::file::bufreader_from_filepath(path.to_path_buf())?;
+
+        let mut mat_u8_sizes = vec![0 as u8; 12];
+        file_handle.read_exact(&mut mat_u8_sizes)?;
+        let nnz = u32::from_le_bytes(mat_u8_sizes[0..4].try_into()?) as usize;
+        let num_bins = u32::from_le_bytes(mat_u8_sizes[4..8].try_into()?);
+        let num_states = u32::from_le_bytes(mat_u8_sizes[8..12].try_into()?);
+
+        let mut probs = vec![0 as u8; nnz];
+        let mut states = vec![0 as u8; nnz];
+        let mut indices = vec![0 as u8; nnz * 4];
+        file_handle.read_exact(&mut probs)?;
+        file_handle.read_exact(&mut states)?;
+        file_handle.read_exact(&mut indices)?;
+
+        let indices = indices
+            .chunks_exact(4)
+            .map(|x| u32::from_le_bytes(x.try_into().unwrap()))
+            .collect();
+
+        Ok(CellPosterior {
+            num_bins,
+            num_states,
+            probs,
+            states,
+            indices,
+        })
+    }
+
+    pub fn num_states(&self) -> u32 {
+        self.num_states
+    }
+
+    pub fn probs(&self) -> &[u8] {
+        &self.probs
+    }
+
+    pub fn states(&self) -> &[u8] {
+        &self.states
+    }
+
+    pub fn indices(&self) -> &[u32] {
+        &self.indices
+    }
+
+    /// keeps only the entries of the states flagged in `keep_states`.
+    pub fn retain_states(&mut self, keep_states: &[bool]) {
+        let keep: Vec<bool> = self
+            .states
+            .iter()
+            .map(|&x| keep_states[x as usize])
+            .collect();
+
+        let filter = |vals: &mut Vec<u8>| {
+            let mut it = keep.iter();
+            vals.retain(|_| *it.next().unwrap());
+        };
+        filter(&mut self.probs);
+        filter(&mut self.states);
+
+        let mut it = keep.iter();
+        self.indices.retain(|_| *it.next().unwrap());
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let nnz = self.probs.len() as u32;
+        let mut bin_mat: Vec<u8> = vec![
+            nnz.to_le_bytes(),
+            self.num_bins.to_le_bytes(),
+            self.num_states.to_le_bytes(),
+        ]
+        .concat();
+
+        bin_mat.extend(&self.probs);
+        bin_mat.extend(&self.states);
+        bin_mat.extend(
+            self.indices
+                .iter()
+                .map(|x| x.to_le_bytes())
+                .collect::<Vec<[u8; 4]>>()
+                .concat(),
+        );
+
+        bin_mat
+    }
+}
+
+////////////////////////////////////////////
+/// State Posterior
+/// "short" format written by `transform`, one file per state:
+/// num_bins, num_cells + 1 (u32), cumulative cell sizes (u32),
+/// nnz probs (u8), nnz bin indices (u32).
+/// An empty file represents a state with no entries.
+////////////////////////////////////////////
+#[derive(Debug)]
+pub struct StatePosterior {
+    num_bins: u32,
+    sizes: Vec<u32>,
+    probs: Vec<u8>,
+    indices: Vec<u32>,
+}
+
+impl StatePosterior {
+    pub fn new(num_bins: u32) -> StatePosterior {
+        StatePosterior {
+            num_bins,
+            sizes: vec![0],
+            probs: Vec::new(),
+            indices: Vec::new(),
+        }
+    }
+
+    pub fn from_path(path: &Path) -> Result<Option<StatePosterior>, Box<dyn Error>> {
+        let mut file_handle = carina::file::bufreader_from_filepath(path.to_path_buf())?;
+
+        let mut bytes = Vec::new();
+        file_handle.read_to_end(&mut bytes)?;
+        if bytes.is_empty() {
+            return Ok(None);
+        }
+
+        let read_u32 = |offset: usize| -> Result<u32, Box<dyn Error>> {
+            let slice = bytes
+                .get(offset..offset + 4)
+                .ok_or_else(|| format!("truncated state file {:?}", path))?;
+            Ok(u32::from_le_bytes(slice.try_into()?))
+        };
+
+        let num_bins = read_u32(0)?;
+        let num_sizes = read_u32(4)? as usize;
+        let sizes: Vec<u32> = (0..num_sizes)
+            .map(|i| read_u32(8 + i * 4))
+            .collect::<Result<_, _>>()?;
+
+        let nnz = *sizes.last().unwrap_or(&0) as usize;
+        let probs_start = 8 + num_sizes * 4;
+        let indices_start = probs_start + nnz;
+        if bytes.len() != indices_start + nnz * 4 {
+            return Err(format!("malformed state file {:?}", path).into());
+        }
+
+        let probs = bytes[probs_start..indices_start].to_vec();
+        let indices = (0..nnz)
+            .map(|i| read_u32(indices_start + i * 4))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Some(StatePosterior {
+            num_bins,
+            sizes,
+            probs,
+            indices,
+        }))
+    }
+
+    pub fn num_bins(&self) -> u32 {
+        self.num_bins
+    }
+
+    pub fn num_cells(&self) -> usize {
+        self.sizes.len() - 1
+    }
+
+    /// (probs, bin indices) of the `cell_id`-th cell of the file.
+    pub fn get_cell(&self, cell_id: usize) -> (&[u8], &[u32]) {
+        let (start, end) = (
+            self.sizes[cell_id] as usize,
+            self.sizes[cell_id + 1] as usize,
+        );
+        (&self.probs[start..end], &self.indices[start..end])
+    }
+
+    pub fn push_cell(&mut self, probs: &[u8], indices: &[u32]) {
+        assert_eq!(probs.len(), indices.len());
+        let running_sum = self.sizes.last().unwrap() + probs.len() as u32;
+        self.sizes.push(running_sum);
+        self.probs.extend(probs);
+        self.indices.extend(indices);
+    }
+
+    pub fn write(&self, path: PathBuf) -> Result<(), Box<dyn Error>> {
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        if self.probs.is_empty() {
+            return Ok(());
+        }
+
+        file.write_all(&self.num_bins.to_le_bytes())?;
+        file.write_all(&(self.sizes.len() as u32).to_le_bytes())?;
+        let bin_sizes: Vec<u8> = self
+            .sizes
+            .iter()
+            .map(|x| x.to_le_bytes())
+            .collect::<Vec<[u8; 4]>>()
+            .concat();
+        file.write_all(&bin_sizes)?;
+        file.write_all(&self.probs)?;
+        let bin_indices: Vec<u8> = self
+            .indices
+            .iter()
+            .map(|x| x.to_le_bytes())
+            .collect::<Vec<[u8; 4]>>()
+            .concat();
+        file.write_all(&bin_indices)?;
+
+        Ok(())
+    }
+}
+
+/// chromosome sub-directories of a scChromHMM output directory, sorted by name.
+pub fn get_chromosomes(in_path: &Path) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut chrs: Vec<String> = std::fs::read_dir(in_path)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_dir())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .collect();
+    chrs.sort();
+
+    Ok(chrs)
+}
+
+/// 1-indexed states with a `<state>.bin` file in a "short" chromosome directory.
+pub fn get_state_files(chr_path: &Path) -> Result<Vec<usize>, Box<dyn Error>> {
+    let mut states: Vec<usize> = std::fs::read_dir(chr_path)?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter_map(|name| name.strip_suffix(".bin").map(|x| x.parse::<usize>().ok()))
+        .flatten()
+        .filter(|&x| x > 0)
+        .collect();
+    states.sort_unstable();
+
+    Ok(states)
+}
+
+/// reads one cell name per line.
+pub fn read_cells(path: PathBuf) -> Result<Vec<String>, Box<dyn Error>> {
+    use std::io::BufRead;
+
+    let reader = carina::file::bufreader_from_filepath(path.clone())
+        .map_err(|e| format!("can't read cells from {:?}: {}", path, e))?;
+    let mut cells = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if !line.is_empty() {
+            cells.push(line);
+        }
+    }
+
+    Ok(cells)
+}
+
+/// (index, number of shards) of a sharded run, if any.
+pub fn read_shard(path: &Path) -> Result<Option<(usize, usize)>, Box<dyn Error>> {
+    let shard_file = path.join(SHARD_FILE);
+    if !shard_file.exists() {
+        return Ok(None);
+    }
+
+    let shard = std::fs::read_to_string(&shard_file)?;
+    let toks: Vec<&str> = shard.trim().split('/').collect();
+    match toks.as_slice() {
+        [index, num_shards] => Ok(Some((index.parse()?, num_shards.parse()?))),
+        _ => Err(format!("can't parse shard in {:?}", shard_file).into()),
+    }
+}
@@ -1,207 +1,1057 @@
-use bio::data_structures::interval_tree::IntervalTree;
+use clap::ArgMatches;
 
-use crate::config::{ProbT, MIN_PROB, WINDOW_SIZE};
+use crate::config::{ProbT, RangeT, MIN_PROB, MISSING_CODE, VALID_STATES, WINDOW_SIZE};
 use crate::model::Hmm;
 use crate::record::CellRecords;
 
+use std::collections::HashMap;
 use std::error::Error;
+use std::ops::{Range, RangeInclusive};
 
-fn forward(
-    observations: &[Vec<ProbT>],
-    hmm: &Hmm,
-    fprob: &mut Vec<Vec<ProbT>>,
-    num_states: usize,
-    num_observations: usize,
-) -> ProbT {
-    let mut f_prev: Vec<ProbT> = (0..num_states)
-        .map(|state| hmm.get_emission_prob(state, &observations[0]) * hmm.get_init_prob(state))
+/// presence combination of the assays of a bin, the i-th bit being set if
+/// the i-th assay is above its threshold.
+fn get_observation_code(observation: &[ProbT], thresholds: &[ProbT]) -> u32 {
+    let mut code: u32 = 0;
+    for (index, &value) in observation.iter().enumerate() {
+        if value > thresholds[index] {
+            code |= 1 << index;
+        }
+    }
+
+    code
+}
+
+fn push_run(runs: &mut Vec<(u32, usize)>, code: u32, len: usize) {
+    if len == 0 {
+        return;
+    }
+
+    match runs.last_mut() {
+        Some((last_code, last_len)) if *last_code == code => *last_len += len,
+        _ => runs.push((code, len)),
+    }
+}
+
+/// runs of the bins in [start, end) without signal, the blacklisted ones
+/// with `MISSING_CODE`.
+fn push_empty_runs(
+    runs: &mut Vec<(u32, usize)>,
+    empty_code: u32,
+    masked_bins: &[bool],
+    start: usize,
+    end: usize,
+) {
+    let mut bin = start;
+    while bin < end {
+        let is_masked = masked_bins.get(bin) == Some(&true);
+        let len = (bin..end)
+            .take_while(|&x| (masked_bins.get(x) == Some(&true)) == is_masked)
+            .count();
+        match is_masked {
+            true => push_run(runs, MISSING_CODE, len),
+            false => push_run(runs, empty_code, len),
+        }
+        bin += len;
+    }
+}
+
+/// bins of the same observation code in a row, as (code, number of bins),
+/// over all the bins of a chromosome. The blacklisted bins have no
+/// observations and make runs of `MISSING_CODE`.
+fn get_observation_runs(
+    observations: &[(usize, Vec<ProbT>)],
+    thresholds: &[ProbT],
+    masked_bins: &[bool],
+    num_bins: usize,
+) -> Vec<(u32, usize)> {
+    let empty_code = get_observation_code(&vec![0.0; thresholds.len()], thresholds);
+
+    let mut runs: Vec<(u32, usize)> = Vec::new();
+    let mut next_bin = 0;
+    for (bin, observation) in observations {
+        push_empty_runs(&mut runs, empty_code, masked_bins, next_bin, *bin);
+        push_run(&mut runs, get_observation_code(observation, thresholds), 1);
+        next_bin = bin + 1;
+    }
+    push_empty_runs(&mut runs, empty_code, masked_bins, next_bin, num_bins);
+
+    runs
+}
+
+fn normalize(probs: &mut [ProbT]) {
+    let prob_norm: ProbT = probs.iter().sum();
+    probs.iter_mut().for_each(|x| *x /= prob_norm);
+}
+
+/// forward probabilities of the next bin, observed with the given code.
+fn step_forward(f_prev: &[ProbT], hmm: &Hmm, code: u32) -> Vec<ProbT> {
+    // one transition row at a time, summing over the previous states in order.
+    let mut f_curr = vec![0.0; f_prev.len()];
+    for (prev_state, &prev_f) in f_prev.iter().enumerate() {
+        f_curr
+            .iter_mut()
+            .zip(hmm.get_transitions(prev_state))
+            .for_each(|(f_item, &transition)| *f_item += prev_f * transition);
+    }
+    f_curr
+        .iter_mut()
+        .zip(hmm.get_emissions(code))
+        .for_each(|(f_item, &emission)| *f_item *= emission);
+    normalize(&mut f_curr);
+
+    f_curr
+}
+
+/// backward probabilities of the previous bin, the next one being
+/// observed with the given code.
+fn step_backward(b_next: &[ProbT], hmm: &Hmm, code: u32) -> Vec<ProbT> {
+    let emissions = hmm.get_emissions(code);
+    let mut b_curr: Vec<ProbT> = (0..b_next.len())
+        .map(|state| {
+            hmm.get_transitions(state)
+                .iter()
+                .zip(emissions)
+                .zip(b_next.iter())
+                .map(|((&transition, &emission), &next_b)| transition * emission * next_b)
+                .sum()
+        })
         .collect();
-    let prob_norm: ProbT = f_prev.iter().sum();
-    f_prev.iter_mut().for_each(|x| *x /= prob_norm);
-    fprob[0].clone_from(&f_prev);
-
-    let mut f_curr = vec![0.0; num_states];
-    for i in 1..num_observations {
-        for (state, f_item) in f_curr.iter_mut().enumerate().take(num_states) {
-            let mut prev_f_sum = 0.0;
-            (0..num_states).for_each(|prev_state| {
-                prev_f_sum += f_prev[prev_state] * hmm.get_transition_prob(prev_state, state);
-            });
-            *f_item = hmm.get_emission_prob(state, &observations[i]) * prev_f_sum;
+    normalize(&mut b_curr);
+
+    b_curr
+}
+
+////////////////////////////////////////////
+/// Run Forward
+/// forward probabilities of the bins of a run, read from its last bin
+/// back. Only the ones of every sqrt(len)-th bin are kept, advanced from
+/// the first bin with the powers of the run matrix, and the bins in
+/// between are stepped from them one block at a time, so that at most
+/// about 2 sqrt(len) vectors are held for a run of any length.
+////////////////////////////////////////////
+struct RunForward {
+    code: u32,
+    len: usize,
+    interval: usize,
+    checkpoints: Vec<Vec<ProbT>>,
+    /// index and forward probabilities of the block last stepped through.
+    block: Option<(usize, Vec<Vec<ProbT>>)>,
+}
+
+impl RunForward {
+    fn new(start: Vec<ProbT>, code: u32, len: usize, matrices: &mut RunMatrices) -> RunForward {
+        let interval = std::cmp::max((len as f64).sqrt().ceil() as usize, 1);
+        let mut checkpoints = Vec::with_capacity(len / interval + 1);
+        checkpoints.push(start);
+        while checkpoints.len() * interval < len {
+            let next = matrices.advance(checkpoints.last().unwrap(), code, interval);
+            checkpoints.push(next);
+        }
+
+        RunForward {
+            code,
+            len,
+            interval,
+            checkpoints,
+            block: None,
+        }
+    }
+
+    /// number of forward probability vectors held.
+    #[cfg(test)]
+    fn num_stored(&self) -> usize {
+        self.checkpoints.len() + self.block.as_ref().map_or(0, |x| x.1.len())
+    }
+
+    /// forward probabilities of the i-th bin into the run, every block
+    /// being stepped through once if read in descending order.
+    fn get(&mut self, i: usize, hmm: &Hmm) -> &[ProbT] {
+        let block = i / self.interval;
+        if self.block.as_ref().map(|x| x.0) != Some(block) {
+            let block_len = std::cmp::min(self.interval, self.len - block * self.interval);
+            let mut probs = Vec::with_capacity(block_len);
+            probs.push(self.checkpoints[block].clone());
+            while probs.len() < block_len {
+                let next = step_forward(probs.last().unwrap(), hmm, self.code);
+                probs.push(next);
+            }
+            self.block = Some((block, probs));
+        }
+
+        &self.block.as_ref().unwrap().1[i % self.interval]
+    }
+}
+
+////////////////////////////////////////////
+/// Run Matrices
+/// transition times emission matrices, M[p][s] = T[p][s] * E[s], of the
+/// codes of a cell along with their powers of two, to advance the forward
+/// probabilities over a run in log(run length) steps. Every power is
+/// scaled by its largest entry, the probabilities being normalised anyway.
+////////////////////////////////////////////
+struct RunMatrices<'a> {
+    hmm: &'a Hmm,
+    powers: HashMap<u32, Vec<Vec<ProbT>>>,
+}
+
+impl<'a> RunMatrices<'a> {
+    fn new(hmm: &'a Hmm) -> RunMatrices<'a> {
+        RunMatrices {
+            hmm,
+            powers: HashMap::new(),
+        }
+    }
+
+    fn get_matrix(hmm: &Hmm, code: u32) -> Vec<ProbT> {
+        let emissions = hmm.get_emissions(code);
+        (0..hmm.num_states())
+            .flat_map(|pstate| {
+                hmm.get_transitions(pstate)
+                    .iter()
+                    .zip(emissions)
+                    .map(|(&transition, &emission)| transition * emission)
+            })
+            .collect()
+    }
+
+    fn square(mat: &[ProbT], num_states: usize) -> Vec<ProbT> {
+        let mut out = vec![0.0; mat.len()];
+        for i in 0..num_states {
+            let out_row = &mut out[i * num_states..(i + 1) * num_states];
+            for k in 0..num_states {
+                let val = mat[i * num_states + k];
+                out_row
+                    .iter_mut()
+                    .zip(&mat[k * num_states..(k + 1) * num_states])
+                    .for_each(|(x, &y)| *x += val * y);
+            }
+        }
+
+        let scale = out.iter().cloned().fold(0.0, ProbT::max);
+        if scale > 0.0 {
+            out.iter_mut().for_each(|x| *x /= scale);
         }
 
-        let prob_norm: ProbT = f_curr.iter().sum();
-        f_curr.iter_mut().for_each(|x| *x /= prob_norm);
+        out
+    }
+
+    /// forward probabilities `steps` bins further into a run of `code`.
+    fn advance(&mut self, f: &[ProbT], code: u32, steps: usize) -> Vec<ProbT> {
+        let (hmm, num_states) = (self.hmm, self.hmm.num_states());
+        let num_powers = (usize::BITS - steps.leading_zeros()) as usize;
+        let powers = self
+            .powers
+            .entry(code)
+            .or_insert_with(|| vec![RunMatrices::get_matrix(hmm, code)]);
+        while powers.len() < num_powers {
+            let next = RunMatrices::square(powers.last().unwrap(), num_states);
+            powers.push(next);
+        }
+
+        let mut f = f.to_vec();
+        for (power, mat) in powers.iter().enumerate().take(num_powers) {
+            if steps >> power & 1 == 0 {
+                continue;
+            }
+
+            let mut f_next = vec![0.0; num_states];
+            for (pstate, &prev_f) in f.iter().enumerate() {
+                f_next
+                    .iter_mut()
+                    .zip(&mat[pstate * num_states..(pstate + 1) * num_states])
+                    .for_each(|(x, &y)| *x += prev_f * y);
+            }
+            normalize(&mut f_next);
+            f = f_next;
+        }
 
-        fprob[i].clone_from(&f_curr);
-        f_prev.clone_from(&f_curr);
+        f
     }
+}
+
+/// forward probabilities of the first bin of the run after `run`, from
+/// the ones of the first bin of `run`.
+fn next_start(
+    f: &[ProbT],
+    runs: &[(u32, usize)],
+    run: usize,
+    hmm: &Hmm,
+    matrices: &mut RunMatrices,
+) -> Vec<ProbT> {
+    let (code, len) = runs[run];
+    let f_end = match len > 1 {
+        true => matrices.advance(f, code, len - 1),
+        false => f.to_vec(),
+    };
+
+    step_forward(&f_end, hmm, runs[run + 1].0)
+}
+
+/// forward probabilities of the first bin of every `interval`-th run.
+fn forward(
+    runs: &[(u32, usize)],
+    hmm: &Hmm,
+    matrices: &mut RunMatrices,
+    interval: usize,
+) -> Vec<Vec<ProbT>> {
+    let mut checkpoints = Vec::with_capacity(runs.len() / interval + 1);
 
-    let mut norm = f_curr.into_iter().map(|x| x * 0.1).sum();
-    if norm == 0.0 {
-        norm = 1.0;
+    let emissions = hmm.get_emissions(runs[0].0);
+    let mut f: Vec<ProbT> = (0..hmm.num_states())
+        .map(|state| emissions[state] * hmm.get_init_prob(state))
+        .collect();
+    normalize(&mut f);
+    for run in 0..runs.len() {
+        if run % interval == 0 {
+            checkpoints.push(f.clone());
+        }
+        if run + 1 < runs.len() {
+            f = next_start(&f, runs, run, hmm, matrices);
+        }
     }
 
-    norm
+    checkpoints
+}
+
+/// what the posteriors of a cell are reported as.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PosteriorOutput {
+    /// the posterior of every state.
+    States,
+    /// the predicted probability of every mark, the posteriors weighted
+    /// by the presence probabilities of the mark in each state.
+    Marks,
+    /// the predicted probability of a single mark, at every bin.
+    Mark(usize),
 }
 
 #[inline]
 fn update_triplet(
     i: usize,
     posterior: &mut Vec<(usize, usize, ProbT)>,
+    f_curr: &[ProbT],
     b_curr: &[ProbT],
-    num_states: usize,
-    norm: ProbT,
-    fprob: &[Vec<ProbT>],
+    hmm: &Hmm,
+    output: PosteriorOutput,
 ) {
-    let is_valid_state = |state: usize| match state {
-        //0 | 1 | 2 | 4 | 10 | 11 => true,
-        0 | 1 | 2 | 3 | 8 | 9 | 11 => true,
-        //0 | 1  => true,
-        _ => false,
-    };
+    let num_states = hmm.num_states();
+    let is_valid_state = |state: usize| VALID_STATES.contains(&state);
 
     let probs: Vec<ProbT> = (0..num_states)
-        .map(|state| fprob[i][state] * b_curr[state] / norm)
+        .map(|state| f_curr[state] * b_curr[state])
         .collect();
     let state_norm: ProbT = probs.iter().sum();
-    probs.into_iter().enumerate().for_each(|(state, prob)| {
-        let prob = prob / state_norm;
-        if (prob > MIN_PROB) & is_valid_state(state) {
-            posterior.push((i, state, prob))
+    match output {
+        PosteriorOutput::States => probs.into_iter().enumerate().for_each(|(state, prob)| {
+            let prob = prob / state_norm;
+            if (prob > MIN_PROB) & is_valid_state(state) {
+                posterior.push((i, state, prob))
+            }
+        }),
+        PosteriorOutput::Marks => (0..hmm.num_marks()).for_each(|mark| {
+            let prob: ProbT = probs
+                .iter()
+                .enumerate()
+                .map(|(state, prob)| prob * hmm.get_presence_prob(state, mark))
+                .sum::<ProbT>()
+                / state_norm;
+            if prob > MIN_PROB {
+                posterior.push((i, mark, prob))
+            }
+        }),
+        PosteriorOutput::Mark(mark) => {
+            let prob: ProbT = probs
+                .iter()
+                .enumerate()
+                .map(|(state, prob)| prob * hmm.get_presence_prob(state, mark))
+                .sum::<ProbT>()
+                / state_norm;
+            posterior.push((i, mark, prob))
         }
-    });
+    }
 }
 
+/// the runs are processed from the last one, with the forward and backward
+/// probabilities of every bin of a run recovered from its two ends, see
+/// `RunForward` for the forward ones. The
+/// forward probabilities of the runs between two checkpoints are recomputed
+/// from the earlier one.
 fn backward(
-    observations: Vec<Vec<ProbT>>,
+    runs: &[(u32, usize)],
     hmm: &Hmm,
-    norm: ProbT,
-    num_states: usize,
-    num_observations: usize,
-    fprob: &[Vec<ProbT>],
+    matrices: &mut RunMatrices,
+    checkpoints: &[Vec<ProbT>],
+    interval: usize,
     posterior: &mut Vec<(usize, usize, ProbT)>,
+    output: PosteriorOutput,
 ) {
-    let mut b_curr = vec![0.1; num_states];
-    let mut b_prev = vec![0.1; num_states];
-
-    update_triplet(
-        num_observations - 1,
-        posterior,
-        &b_curr,
-        num_states,
-        norm,
-        fprob,
-    );
-    for i in (1..num_observations).rev() {
-        let obv_emissions: Vec<ProbT> = (0..num_states)
-            .into_iter()
-            .map(|state| hmm.get_emission_prob(state, &observations[i]))
-            .collect();
-
-        b_curr.iter_mut().for_each(|i| *i = 0.0);
-        for (state, b_item) in b_curr.iter_mut().enumerate().take(num_states) {
-            for next_state in 0..num_states {
-                *b_item += hmm.get_transition_prob(state, next_state)
-                    * obv_emissions[next_state]
-                    * b_prev[next_state];
-            }
+    let mut b = vec![0.1; hmm.num_states()];
+    let mut end: usize = runs.iter().map(|x| x.1).sum();
+    for (block, checkpoint) in checkpoints.iter().enumerate().rev() {
+        let first_run = block * interval;
+        let last_run = std::cmp::min(first_run + interval, runs.len());
+        let mut starts = vec![checkpoint.clone()];
+        for run in first_run..last_run - 1 {
+            let next = next_start(starts.last().unwrap(), runs, run, hmm, matrices);
+            starts.push(next);
         }
 
-        let prob_norm: ProbT = b_curr.iter().sum();
-        b_curr.iter_mut().for_each(|x| *x /= prob_norm);
+        for run in (first_run..last_run).rev() {
+            let (code, len) = runs[run];
+            let start = end - len;
+            let mut fs = RunForward::new(starts.pop().unwrap(), code, len, matrices);
+            for i in (0..len).rev() {
+                if i + 1 < len {
+                    b = step_backward(&b, hmm, code);
+                }
+                update_triplet(start + i, posterior, fs.get(i, hmm), &b, hmm, output);
+            }
 
-        b_prev.clone_from(&b_curr);
-        update_triplet(i - 1, posterior, &b_curr, num_states, norm, fprob);
+            b = step_backward(&b, hmm, code);
+            end = start;
+        }
     }
 }
 
+/// how forward-backward is run and what it reports.
+#[derive(Debug, Clone, Copy)]
+pub struct PosteriorOptions {
+    pub output: PosteriorOutput,
+    /// keeps the forward probabilities of only every sqrt(n)-th of the n runs,
+    /// recomputing the others in the backward pass.
+    pub checkpoint: bool,
+}
+
 fn get_posterior(
-    observations: Vec<Vec<ProbT>>,
+    runs: &[(u32, usize)],
     hmm: &Hmm,
-    fprob: &mut Vec<Vec<ProbT>>,
     posterior: &mut Vec<(usize, usize, ProbT)>,
+    options: PosteriorOptions,
 ) {
-    let num_states = hmm.num_states();
-    let num_assays = hmm.num_assays();
-    let num_observations = observations.len();
-
-    assert!(num_assays == observations[0].len());
-    let norm = forward(&observations, &hmm, fprob, num_states, num_observations);
+    let interval = match options.checkpoint {
+        true => (runs.len() as f64).sqrt().ceil() as usize,
+        false => 1,
+    };
 
+    let mut matrices = RunMatrices::new(hmm);
+    let checkpoints = forward(runs, hmm, &mut matrices, interval);
     backward(
-        observations,
-        &hmm,
-        norm,
-        num_states,
-        num_observations,
-        fprob,
+        runs,
+        hmm,
+        &mut matrices,
+        &checkpoints,
+        interval,
         posterior,
+        options.output,
     );
 }
 
+/// how the fragments of a cell are counted into the bins.
+#[derive(Debug, Clone, Copy)]
+pub enum ObservationMode {
+    /// every bin the fragment overlaps.
+    Overlap,
+    /// the bins of the two Tn5 cut sites, start and end shifted by the offsets.
+    CutSites(i64, i64),
+    /// the bin of the fragment midpoint.
+    Midpoint,
+}
+
+/// how the observations of a cell are counted and binarised.
+#[derive(Debug, Clone, Copy)]
+pub struct ObservationOptions {
+    pub mode: ObservationMode,
+    pub thresholds: ThresholdMode,
+}
+
+impl ObservationOptions {
+    pub fn from_clap(sub_m: &ArgMatches) -> Result<ObservationOptions, Box<dyn Error>> {
+        Ok(ObservationOptions {
+            mode: ObservationMode::from_clap(sub_m)?,
+            thresholds: ThresholdMode::from_clap(sub_m)?,
+        })
+    }
+}
+
+impl ObservationMode {
+    pub fn from_clap(sub_m: &ArgMatches) -> Result<ObservationMode, Box<dyn Error>> {
+        let mode = match sub_m.value_of("count_mode").unwrap_or("overlap") {
+            "overlap" => ObservationMode::Overlap,
+            "cutsites" => ObservationMode::CutSites(
+                sub_m.value_of("cut_shift_start").unwrap_or("0").parse()?,
+                sub_m.value_of("cut_shift_end").unwrap_or("0").parse()?,
+            ),
+            "midpoint" => ObservationMode::Midpoint,
+            mode => return Err(format!("unknown count mode {}", mode).into()),
+        };
+
+        Ok(mode)
+    }
+}
+
+/// how the observations of a cell are binarised.
+#[derive(Debug, Clone, Copy)]
+pub enum ThresholdMode {
+    /// the fixed per assay thresholds of the model.
+    Fixed,
+    /// per cell thresholds, the smallest fragment count with a p-value
+    /// below the given one under a Poisson background of the cell's own
+    /// mean fragments per bin.
+    Poisson(f64),
+}
+
+impl ThresholdMode {
+    pub fn from_clap(sub_m: &ArgMatches) -> Result<ThresholdMode, Box<dyn Error>> {
+        let mode = match sub_m.value_of("threshold_mode").unwrap_or("fixed") {
+            "fixed" => ThresholdMode::Fixed,
+            "poisson" => {
+                let pvalue = sub_m
+                    .value_of("threshold_pvalue")
+                    .unwrap_or("0.01")
+                    .parse()?;
+                if pvalue <= 0.0 || pvalue >= 1.0 {
+                    return Err(format!("threshold p-value {} not in (0, 1)", pvalue).into());
+                }
+                ThresholdMode::Poisson(pvalue)
+            }
+            mode => return Err(format!("unknown threshold mode {}", mode).into()),
+        };
+
+        Ok(mode)
+    }
+}
+
+/// smallest count k with P(X >= k) <= pvalue for X ~ Poisson(lambda).
+fn get_poisson_cutoff(lambda: f64, pvalue: f64) -> usize {
+    let mut pmf = (-lambda).exp();
+    let mut cdf = pmf;
+    let mut k = 1;
+    // stops around the mean if the pmf underflows for very large lambda.
+    while 1.0 - cdf > pvalue && (pmf > 0.0 || (k as f64) < lambda) {
+        pmf *= lambda / k as f64;
+        cdf += pmf;
+        k += 1;
+    }
+
+    k
+}
+
+/// (mean count per bin, mean weight per count) of the binned records of
+/// every assay over the bins that aren't blacklisted, a record counting
+/// once in every bin it is counted in by the count mode.
+fn get_background(
+    cell_records: &[&CellRecords<ProbT>],
+    chr_len: usize,
+    masked_bins: &[bool],
+    mode: ObservationMode,
+) -> Vec<(f64, ProbT)> {
+    let num_bins = (chr_len + WINDOW_SIZE - 1) / WINDOW_SIZE;
+    let is_counted = |bin: usize| bin < num_bins && masked_bins.get(bin) != Some(&true);
+    let num_counted_bins = (0..num_bins).filter(|&x| is_counted(x)).count();
+
+    cell_records
+        .iter()
+        .map(|cell_records| {
+            let (mut count, mut weight) = (0, 0.0);
+            for record in cell_records.records() {
+                for_each_record_bin(record.range(), mode, |bin| {
+                    if is_counted(bin) {
+                        count += 1;
+                        weight += record.id();
+                    }
+                });
+            }
+
+            match count {
+                0 => (0.0, 0.0),
+                _ => (
+                    count as f64 / num_counted_bins as f64,
+                    weight / count as ProbT,
+                ),
+            }
+        })
+        .collect()
+}
+
+/// per assay binarisation thresholds of a cell. The Poisson cutoff is
+/// converted from binned counts to the signal by the cell's mean weight
+/// per count.
+fn get_thresholds(
+    cell_records: &[&CellRecords<ProbT>],
+    hmm: &Hmm,
+    options: ObservationOptions,
+    chr_len: usize,
+    masked_bins: &[bool],
+) -> Vec<ProbT> {
+    match options.thresholds {
+        ThresholdMode::Fixed => hmm.get_thresholds(),
+        ThresholdMode::Poisson(pvalue) => {
+            get_background(cell_records, chr_len, masked_bins, options.mode)
+                .into_iter()
+                .map(|(lambda, weight)| match lambda > 0.0 {
+                    true => (get_poisson_cutoff(lambda, pvalue) as ProbT - 0.5) * weight,
+                    false => 0.0,
+                })
+                .collect()
+        }
+    }
+}
+
+/// bins overlapping a record, as queried by the earlier per bin interval
+/// trees: bin i spans [200i - 1, 200i + 200), and the first one [0, 201).
+fn get_overlap_bins(range: &Range<RangeT>) -> RangeInclusive<usize> {
+    let (start, end) = (range.start as usize, range.end as usize);
+    let first = match start <= WINDOW_SIZE {
+        true => 0,
+        false => start / WINDOW_SIZE,
+    };
+
+    first..=end / WINDOW_SIZE
+}
+
+/// bins a record is counted in, once for every count.
+fn for_each_record_bin(range: &Range<RangeT>, mode: ObservationMode, mut f: impl FnMut(usize)) {
+    let (start, end) = (range.start as i64, range.end as i64);
+    let point_bin = |pos: i64| std::cmp::max(pos, 0) as usize / WINDOW_SIZE;
+    match mode {
+        ObservationMode::Overlap => get_overlap_bins(range).for_each(f),
+        ObservationMode::CutSites(start_shift, end_shift) => {
+            f(point_bin(start + start_shift));
+            f(point_bin(end - 1 + end_shift));
+        }
+        ObservationMode::Midpoint => f(point_bin((start + end) / 2)),
+    }
+}
+
+/// anchor imputed signal of the bins with any, sorted by bin, as
+/// (bin, signal per assay). The records are summed into every bin they
+/// are counted in, in order, linear in the records and bins of the cell.
+pub fn get_observations(
+    cell_records: Vec<&CellRecords<ProbT>>,
+    chr_len: usize,
+    masked_bins: &[bool],
+    mode: ObservationMode,
+) -> Vec<(usize, Vec<ProbT>)> {
+    let num_bins = (chr_len + WINDOW_SIZE - 1) / WINDOW_SIZE;
+    let num_assays = cell_records.len();
+
+    // signal of every bin and assay, bin major.
+    let mut signal: Vec<ProbT> = vec![0.0; num_bins * num_assays];
+    let mut has_signal = vec![false; num_bins];
+    for (assay, cell_records) in cell_records.into_iter().enumerate() {
+        for record in cell_records.records() {
+            for_each_record_bin(record.range(), mode, |bin| {
+                // blacklisted bins have no signal.
+                if bin >= num_bins || masked_bins.get(bin) == Some(&true) {
+                    return;
+                }
+                signal[bin * num_assays + assay] += record.id();
+                has_signal[bin] = true;
+            });
+        }
+    }
+
+    (0..num_bins)
+        .filter(|&bin| has_signal[bin])
+        .map(|bin| {
+            (
+                bin,
+                signal[bin * num_assays..(bin + 1) * num_assays].to_vec(),
+            )
+        })
+        .collect()
+}
+
 pub fn run_fwd_bkw(
     cell_records: Vec<&CellRecords<ProbT>>,
     hmm: &Hmm,
-    fprob: &mut Vec<Vec<ProbT>>,
     posterior: &mut Vec<(usize, usize, ProbT)>,
     chr_len: usize,
+    masked_bins: &[bool],
+    options: ObservationOptions,
+    posterior_options: PosteriorOptions,
 ) -> Result<(), Box<dyn Error>> {
-    let itrees: Vec<IntervalTree<u32, ProbT>> = cell_records
-        .into_iter()
-        .map(|cell_records| {
-            let mut tree = IntervalTree::new();
-            for record in cell_records.records() {
-                tree.insert(record.range(), record.id());
+    let num_bins = (chr_len + WINDOW_SIZE - 1) / WINDOW_SIZE;
+    let thresholds = get_thresholds(&cell_records, hmm, options, chr_len, masked_bins);
+    let observations = get_observations(cell_records, chr_len, masked_bins, options.mode);
+    let runs = get_observation_runs(&observations, &thresholds, masked_bins, num_bins);
+    drop(observations);
+
+    get_posterior(&runs, hmm, posterior, posterior_options);
+
+    Ok(())
+}
+
+/// the predicted probability of every measured mark with its own assay held
+/// out, and whether the assay is observed above its threshold, at every bin.
+/// `holdout_hmms[i]` has the mark of the i-th assay marginalised out.
+pub fn run_holdout(
+    cell_records: Vec<&CellRecords<ProbT>>,
+    hmm: &Hmm,
+    holdout_hmms: &[Hmm],
+    chr_len: usize,
+    masked_bins: &[bool],
+    options: ObservationOptions,
+    checkpoint: bool,
+) -> Vec<(Vec<ProbT>, Vec<bool>)> {
+    let num_bins = (chr_len + WINDOW_SIZE - 1) / WINDOW_SIZE;
+    let thresholds = get_thresholds(&cell_records, hmm, options, chr_len, masked_bins);
+    let observations = get_observations(cell_records, chr_len, masked_bins, options.mode);
+    let runs = get_observation_runs(&observations, &thresholds, masked_bins, num_bins);
+    drop(observations);
+
+    let mut posterior = Vec::with_capacity(num_bins);
+    (0..hmm.num_assays())
+        .map(|assay| {
+            let labels: Vec<bool> = runs
+                .iter()
+                .flat_map(|&(code, len)| {
+                    std::iter::repeat(code != MISSING_CODE && code >> assay & 1 == 1).take(len)
+                })
+                .collect();
+
+            // the codes of the remaining assays, shifting down the higher bits.
+            let low_bits = (1 << assay) - 1;
+            let mut holdout_runs = Vec::new();
+            for &(code, len) in runs.iter() {
+                let holdout_code = match code {
+                    MISSING_CODE => MISSING_CODE,
+                    _ => (code & low_bits) | (code >> (assay + 1) << assay),
+                };
+                push_run(&mut holdout_runs, holdout_code, len);
+            }
+
+            let mark = hmm.assays()[assay];
+            posterior.clear();
+            get_posterior(
+                &holdout_runs,
+                &holdout_hmms[assay],
+                &mut posterior,
+                PosteriorOptions {
+                    output: PosteriorOutput::Mark(mark),
+                    checkpoint,
+                },
+            );
+
+            let mut predictions = vec![0.0; labels.len()];
+            for &(i, _, prob) in posterior.iter() {
+                predictions[i] = prob;
             }
-            tree
+            (predictions, labels)
         })
-        .collect();
+        .collect()
+}
 
-    let get_obv_list = |start: usize, end: usize| {
-        let observation_list: Vec<Vec<ProbT>> = (start..end)
-            .step_by(WINDOW_SIZE)
-            .map(|qstart| {
-                let qstart: usize = std::cmp::max(0, qstart as i32 - 1) as usize;
-                let qrange = qstart as u32..(qstart + WINDOW_SIZE + 1) as u32;
-                let cts: Vec<ProbT> = itrees
-                    .iter()
-                    .map(|tree| {
-                        let vals: Vec<ProbT> = tree.find(&qrange).map(|x| *x.data()).collect();
-                        vals.iter().sum()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::Record;
+
+    /// model of two marks from its initial, transition and presence
+    /// probabilities.
+    fn get_test_hmm(
+        name: &str,
+        init: &[ProbT],
+        transitions: &[Vec<ProbT>],
+        presence: &[[ProbT; 2]],
+    ) -> Hmm {
+        let path = std::env::temp_dir().join(format!("schrom_test_{}.txt", name));
+        let mut model = format!("{}\t2\tE\tchr\t0\n", init.len());
+        for (state, prob) in init.iter().enumerate() {
+            model += &format!("probinit\t{}\t{}\n", state + 1, prob);
+        }
+        for (pstate, row) in transitions.iter().enumerate() {
+            for (state, prob) in row.iter().enumerate() {
+                model += &format!("transitionprobs\t{}\t{}\t{}\n", pstate + 1, state + 1, prob);
+            }
+        }
+        for (state, marks) in presence.iter().enumerate() {
+            for (mark, prob) in marks.iter().enumerate() {
+                let line = format!("emissionprobs\t{}\t{}\tm{}", state + 1, mark, mark);
+                model += &format!("{}\t0\t{}\n", line, 1.0 - prob);
+                model += &format!("{}\t1\t{}\n", line, prob);
+            }
+        }
+        std::fs::write(&path, model).unwrap();
+
+        let hmm = Hmm::new(std::io::BufReader::new(std::fs::File::open(&path).unwrap()));
+        std::fs::remove_file(path).unwrap();
+        hmm
+    }
+
+    /// 3 states with sticky transitions, so that the probabilities take
+    /// long to converge within a run.
+    fn get_sticky_hmm(name: &str) -> Hmm {
+        let transitions: Vec<Vec<ProbT>> = (0..3)
+            .map(|pstate| {
+                (0..3)
+                    .map(|state| match pstate == state {
+                        true => 0.998,
+                        false => 0.001,
                     })
-                    .collect();
-                cts
+                    .collect()
             })
             .collect();
-        observation_list
-    };
+        let presence = [[0.9, 0.05], [0.1, 0.8], [0.02, 0.03]];
+        get_test_hmm(name, &[0.5, 0.3, 0.2], &transitions, &presence)
+    }
 
-    //let qstart = 132492 * 200;
-    //let qrange = qstart as u32 - 1..(qstart + WINDOW_SIZE) as u32;
-    //let cts: Vec<Vec<(u32, u32, f32)>> = itrees
-    //    .iter()
-    //    .map(|tree| {
-    //        let vals: Vec<(u32, u32, f32)> = tree.find(&qrange).map(|x| (x.interval().start, x.interval().end, *x.data())).collect();
-    //        vals
-    //    })
-    //    .collect();
-    //println!("{:?}", qrange);
-    //println!("{:?}", cts);
-
-    let observation_list = get_obv_list(0, chr_len);
-    //println!("{:?}", observation_list[132491]);
-    //println!("{:?}", observation_list[132492]);
-    //println!("{:?}", observation_list[132493]);
-    //println!("{:?}", observation_list[132494]);
-
-    get_posterior(observation_list, hmm, fprob, posterior);
+    /// 4 states with uneven transitions, as fitted by ChromHMM.
+    fn get_fitted_hmm(name: &str) -> Hmm {
+        let transitions = vec![
+            vec![0.9613, 0.0207, 0.0112, 0.0068],
+            vec![0.0311, 0.9372, 0.0094, 0.0223],
+            vec![0.0046, 0.0138, 0.9781, 0.0035],
+            vec![0.0175, 0.0409, 0.0052, 0.9364],
+        ];
+        let presence = [
+            [0.8731, 0.0412],
+            [0.2215, 0.7163],
+            [0.0087, 0.0139],
+            [0.5378, 0.3921],
+        ];
+        get_test_hmm(
+            name,
+            &[0.1823, 0.2417, 0.4935, 0.0825],
+            &transitions,
+            &presence,
+        )
+    }
 
-    Ok(())
-}
+    fn get_test_runs() -> Vec<(u32, usize)> {
+        vec![
+            (0, 5000),
+            (1, 1),
+            (3, 3),
+            (0, 20000),
+            (2, 1),
+            (0, 7),
+            (MISSING_CODE, 300),
+            (2, 40),
+            (0, 1000),
+        ]
+    }
+
+    /// the predicted probability of a mark at every bin, stepping forward
+    /// and backward through every bin.
+    fn get_dense_prediction(runs: &[(u32, usize)], hmm: &Hmm, mark: usize) -> Vec<ProbT> {
+        let codes: Vec<u32> = runs
+            .iter()
+            .flat_map(|&(code, len)| std::iter::repeat(code).take(len))
+            .collect();
+
+        let emissions = hmm.get_emissions(codes[0]);
+        let mut f: Vec<ProbT> = (0..hmm.num_states())
+            .map(|state| emissions[state] * hmm.get_init_prob(state))
+            .collect();
+        normalize(&mut f);
+        let mut fs = vec![f];
+        for &code in &codes[1..] {
+            let f = step_forward(fs.last().unwrap(), hmm, code);
+            fs.push(f);
+        }
+
+        let mut predictions = vec![0.0; codes.len()];
+        let mut b = vec![0.1; hmm.num_states()];
+        for i in (0..codes.len()).rev() {
+            if i + 1 < codes.len() {
+                b = step_backward(&b, hmm, codes[i + 1]);
+            }
+            let probs: Vec<ProbT> = fs[i].iter().zip(b.iter()).map(|(x, y)| x * y).collect();
+            let state_norm: ProbT = probs.iter().sum();
+            predictions[i] = probs
+                .iter()
+                .enumerate()
+                .map(|(state, prob)| prob * hmm.get_presence_prob(state, mark))
+                .sum::<ProbT>()
+                / state_norm;
+        }
+
+        predictions
+    }
+
+    fn get_prediction(
+        runs: &[(u32, usize)],
+        hmm: &Hmm,
+        mark: usize,
+        checkpoint: bool,
+    ) -> Vec<ProbT> {
+        let mut posterior = Vec::new();
+        let options = PosteriorOptions {
+            output: PosteriorOutput::Mark(mark),
+            checkpoint,
+        };
+        get_posterior(runs, hmm, &mut posterior, options);
+
+        let mut predictions = vec![0.0; runs.iter().map(|x| x.1).sum()];
+        for (i, _, prob) in posterior {
+            predictions[i] = prob;
+        }
+        predictions
+    }
+
+    #[test]
+    fn test_run_posterior_dense() {
+        let runs = get_test_runs();
+        let hmms = [
+            get_sticky_hmm("run_posterior_sticky"),
+            get_fitted_hmm("run_posterior_fitted"),
+        ];
+        for hmm in hmms.iter() {
+            for mark in 0..2 {
+                let dense = get_dense_prediction(&runs, hmm, mark);
+                let predictions = get_prediction(&runs, hmm, mark, false);
+                assert_eq!(dense.len(), predictions.len());
+                for (i, (x, y)) in dense.iter().zip(predictions.iter()).enumerate() {
+                    assert!((x - y).abs() < 1e-5, "bin {}: {} vs {}", i, x, y);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_run_forward_memory() {
+        let hmm = get_fitted_hmm("run_forward_memory");
+        let mut matrices = RunMatrices::new(&hmm);
+        let len = 20000;
+        let mut fs = RunForward::new(vec![0.25; 4], 0, len, &mut matrices);
+
+        let mut f = vec![0.25; 4];
+        let mut max_stored = 0;
+        let mut dense = vec![f.clone()];
+        for _ in 1..len {
+            f = step_forward(&f, &hmm, 0);
+            dense.push(f.clone());
+        }
+        for i in (0..len).rev() {
+            for (x, y) in fs.get(i, &hmm).iter().zip(dense[i].iter()) {
+                assert!((x - y).abs() < 1e-5, "bin {}: {} vs {}", i, x, y);
+            }
+            max_stored = std::cmp::max(max_stored, fs.num_stored());
+        }
+
+        // sqrt(20000) rounds up to 142.
+        assert!(max_stored <= 2 * 142 + 1, "{} vectors held", max_stored);
+    }
+
+    #[test]
+    fn test_checkpoint_posterior() {
+        let hmm = get_fitted_hmm("checkpoint_posterior");
+        let runs = get_test_runs();
+        for mark in 0..2 {
+            assert_eq!(
+                get_prediction(&runs, &hmm, mark, false),
+                get_prediction(&runs, &hmm, mark, true)
+            );
+        }
+    }
+
+    #[test]
+    fn test_overlap_bins() {
+        // bin 0 spans [0, 201) and bin i > 0 [200i - 1, 200i + 200).
+        let overlaps = |bin: usize, start: usize, end: usize| match bin {
+            0 => start < 201,
+            _ => 200 * bin - 1 < end && start < 200 * bin + 200,
+        };
+        for start in 0..800 {
+            for end in start + 1..900 {
+                let bins = get_overlap_bins(&(start as RangeT..end as RangeT));
+                let expected: Vec<usize> = (0..6).filter(|&x| overlaps(x, start, end)).collect();
+                assert_eq!(bins.collect::<Vec<usize>>(), expected, "{}-{}", start, end);
+            }
+        }
+    }
+
+    #[test]
+    fn test_observation_boundaries() {
+        // record coordinates, one less than the fragment file start.
+        let masked_bins = vec![false; 5];
+        let get_bins = |start: RangeT, end: RangeT, mode: ObservationMode| {
+            let records = CellRecords::new(vec![Record::new_with_id(&(start..end), 1.0)]);
+            get_observations(vec![&records], 1000, &masked_bins, mode)
+        };
+
+        let overlap = ObservationMode::Overlap;
+        assert_eq!(
+            get_bins(199, 200, overlap),
+            [(0, vec![1.0]), (1, vec![1.0])]
+        );
+        assert_eq!(
+            get_bins(200, 399, overlap),
+            [(0, vec![1.0]), (1, vec![1.0])]
+        );
+        assert_eq!(
+            get_bins(201, 400, overlap),
+            [(1, vec![1.0]), (2, vec![1.0])]
+        );
+
+        let cutsites = ObservationMode::CutSites(0, 0);
+        assert_eq!(get_bins(199, 200, cutsites), [(0, vec![2.0])]);
+        assert_eq!(get_bins(200, 399, cutsites), [(1, vec![2.0])]);
+        assert_eq!(get_bins(201, 400, cutsites), [(1, vec![2.0])]);
+
+        let midpoint = ObservationMode::Midpoint;
+        assert_eq!(get_bins(199, 200, midpoint), [(0, vec![1.0])]);
+        assert_eq!(get_bins(200, 399, midpoint), [(1, vec![1.0])]);
+        assert_eq!(get_bins(201, 400, midpoint), [(1, vec![1.0])]);
+    }
+
+    #[test]
+    fn test_poisson_cutoff() {
+        // P(X >= 5) = 0.0037 and P(X >= 4) = 0.019 for X ~ Poisson(1).
+        assert_eq!(get_poisson_cutoff(1.0, 0.01), 5);
+        // P(X >= 2) = 0.0047 and P(X >= 1) = 0.095.
+        assert_eq!(get_poisson_cutoff(0.1, 0.01), 2);
+        // P(X >= 16) = 0.049 and P(X >= 15) = 0.083.
+        assert_eq!(get_poisson_cutoff(10.0, 0.05), 16);
+        assert_eq!(get_poisson_cutoff(0.0, 0.01), 1);
+    }
+
+    #[test]
+    fn test_background() {
+        // 5 bins of which bin 2 is blacklisted.
+        let mut masked_bins = vec![false; 5];
+        masked_bins[2] = true;
+        let records = CellRecords::new(vec![Record::new_with_id(&(199..400), 2.0)]);
+        let get_lambda =
+            |mode: ObservationMode| get_background(&[&records], 1000, &masked_bins, mode)[0];
+
+        // bins 0, 1 and the blacklisted 2.
+        assert_eq!(get_lambda(ObservationMode::Overlap), (2.0 / 4.0, 2.0));
+        // bins 0 and 1.
+        assert_eq!(
+            get_lambda(ObservationMode::CutSites(0, 0)),
+            (2.0 / 4.0, 2.0)
+        );
+        // bin 1.
+        assert_eq!(get_lambda(ObservationMode::Midpoint), (1.0 / 4.0, 2.0));
+        // both cut sites shifted into the blacklisted bin.
+        assert_eq!(get_lambda(ObservationMode::CutSites(300, 1)), (0.0, 0.0));
+    }
+
+    #[test]
+    fn test_observation_runs_masked() {
+        let observations = vec![(1, vec![1.0, 0.0]), (2, vec![1.0, 1.0])];
+        let mut masked_bins = vec![false; 8];
+        masked_bins[4] = true;
+        masked_bins[5] = true;
+        masked_bins[7] = true;
+
+        let runs = get_observation_runs(&observations, &[0.5, 0.5], &masked_bins, 8);
+        assert_eq!(
+            runs,
+            [
+                (0, 1),
+                (1, 1),
+                (3, 1),
+                (0, 1),
+                (MISSING_CODE, 2),
+                (0, 1),
+                (MISSING_CODE, 1)
+            ]
+        );
+    }
 
-#[cfg(test)]
-mod tests {
     //#[test]
     //fn test_fwd_bkw() {
     //    let path = std::path::PathBuf::from("/mnt/scratch1/avi/Indus/data/model_test.txt");
@@ -1,6 +1,8 @@
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
 use std::ops::Range;
 
+use crate::barcode::BarcodeTable;
 use crate::config::{ProbT, RangeT};
 
 #[derive(Debug, Clone)]
@@ -32,50 +34,51 @@ where
     }
 }
 
-impl Record<u64> {
-    pub fn from_string(
-        data: String,
-        assay_cells: &HashMap<u64, HashMap<u32, ProbT>>,
-    ) -> Option<Record<u64>> {
-        let (mut start, mut end) = (0, 0);
-        let mut cb: &str = "chr0";
-        let mut id: u8 = 0;
-        for (index, text) in data.split_whitespace().enumerate() {
-            match index {
-                1 => start = text.parse::<u32>().unwrap(),
-                2 => end = text.parse::<u32>().unwrap(),
-                3 => {
-                    let toks: Vec<&str> = text.split('-').collect();
-                    cb = toks[0];
-                    id = toks
-                        .get(1)
-                        .unwrap_or_else(|| panic!("{:?}", toks.get(0)))
-                        .parse::<u8>()
-                        .unwrap();
-                }
-                4 => break,
-                _ => (),
-            }
-        }
-
-        let id = match id {
-            1 => carina::barcode::cb_string_to_u64_with_id(cb.as_bytes(), 16, 1).unwrap(),
-            2 => carina::barcode::cb_string_to_u64_with_id(cb.as_bytes(), 16, 2).unwrap(),
-            _ => unreachable!(),
+impl Record<u32> {
+    /// record of a fragment with the interned id of its cellular barcode,
+    /// if the cell is anchored in the assay. `start` and `end` are 0-based,
+    /// end exclusive, as in fragment files and BAM records; the record
+    /// starts one base earlier (1-offset) whatever the input.
+    pub fn from_fragment(
+        start: RangeT,
+        end: RangeT,
+        barcode: &str,
+        barcodes: &BarcodeTable,
+        library_suffix: Option<&str>,
+    ) -> Option<Record<u32>> {
+        let id = barcodes.get(barcode, library_suffix)?;
+        let range = Range {
+            start: start.saturating_sub(1),
+            end,
         };
+        Some(Record { range, id })
+    }
+}
 
-        match assay_cells.contains_key(&id) {
-            true => {
-                // 1-offset
-                let range = Range {
-                    start: start - 1,
-                    end: end,
-                };
-                Some(Record { range, id })
+/// start, end, cellular barcode and duplicate count of a fragment file
+/// line. The count (5th column) is only parsed if `count_duplicates`, and
+/// is 1 otherwise.
+pub fn parse_fragment(
+    data: &str,
+    count_duplicates: bool,
+) -> Result<(RangeT, RangeT, &str, u32), String> {
+    let (mut start, mut end, mut count) = (0, 0, 1);
+    let mut cb: &str = "chr0";
+    for (index, text) in data.split_whitespace().enumerate() {
+        match index {
+            1 => start = text.parse::<u32>().unwrap(),
+            2 => end = text.parse::<u32>().unwrap(),
+            3 => cb = text,
+            4 if count_duplicates => {
+                count = text
+                    .parse::<u32>()
+                    .map_err(|_| format!("can't parse duplicate count {}", text))?
             }
-            false => None,
+            _ => (),
         }
     }
+
+    Ok((start, end, cb, count))
 }
 
 ////////////////////////////////////////////
@@ -98,19 +101,71 @@ impl CellRecords<ProbT> {
 
 ////////////////////////////////////////////
 /// Assay Records
+/// records of an assay indexed by query cell, along with the anchors of
+/// every reference cell. The records of a reference cell are built only
+/// when it is processed, instead of copying every record to all of its
+/// anchored reference cells upfront.
 ////////////////////////////////////////////
 #[derive(Debug)]
 pub struct AssayRecords<R> {
-    records: Vec<CellRecords<R>>,
+    /// (fetch order, record) of every query cell.
+    query_records: Vec<Vec<(u32, Record<R>)>>,
+    /// (query cell, anchor weight) of every reference cell.
+    anchors: Vec<Vec<(u32, R)>>,
+    /// depth normalisation factor of every reference cell, if any.
+    reference_scale: Vec<R>,
 }
 
 impl AssayRecords<ProbT> {
-    pub fn new(records: Vec<CellRecords<ProbT>>) -> AssayRecords<ProbT> {
-        AssayRecords { records }
+    pub fn new(
+        query_records: Vec<Vec<(u32, Record<ProbT>)>>,
+        anchors: Vec<Vec<(u32, ProbT)>>,
+        reference_scale: Vec<ProbT>,
+    ) -> AssayRecords<ProbT> {
+        AssayRecords {
+            query_records,
+            anchors,
+            reference_scale,
+        }
     }
 
-    pub fn get_cell_records(&self, cell_id: usize) -> Option<&CellRecords<ProbT>> {
-        self.records.get(cell_id)
+    /// records of the anchored query cells weighted by their anchor, in
+    /// the order they were fetched. The records of every query cell are
+    /// already in that order, so they are merged, O(F log K) for F records
+    /// over K anchors.
+    pub fn get_cell_records(&self, cell_id: usize) -> CellRecords<ProbT> {
+        let scale = *self.reference_scale.get(cell_id).unwrap_or(&1.0);
+
+        let streams: Vec<(&[(u32, Record<ProbT>)], ProbT)> = self
+            .anchors
+            .get(cell_id)
+            .into_iter()
+            .flatten()
+            .map(|&(cb, prob)| (self.query_records[cb as usize].as_slice(), prob))
+            .collect();
+        let num_records = streams.iter().map(|x| x.0.len()).sum();
+
+        // (fetch order, stream) of the next record of every stream.
+        let mut heap: BinaryHeap<Reverse<(u32, usize)>> = streams
+            .iter()
+            .enumerate()
+            .filter_map(|(stream, x)| x.0.first().map(|y| Reverse((y.0, stream))))
+            .collect();
+        let mut positions = vec![0; streams.len()];
+        let mut records = Vec::with_capacity(num_records);
+        while let Some(Reverse((_, stream))) = heap.pop() {
+            let (query_records, prob) = streams[stream];
+            let record = &query_records[positions[stream]].1;
+            let nprob = record.id() * prob * scale;
+            records.push(Record::new_with_id(record.range(), nprob));
+
+            positions[stream] += 1;
+            if let Some((index, _)) = query_records.get(positions[stream]) {
+                heap.push(Reverse((*index, stream)));
+            }
+        }
+
+        CellRecords::new(records)
     }
 }
 
@@ -127,10 +182,48 @@ impl Experiment<ProbT> {
         Experiment { records }
     }
 
-    pub fn get_cell_data(&self, cell_id: usize) -> Vec<&CellRecords<ProbT>> {
+    pub fn num_assays(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn get_cell_data(&self, cell_id: usize) -> Vec<CellRecords<ProbT>> {
         self.records
             .iter()
-            .map(|x| x.get_cell_records(cell_id).unwrap())
+            .map(|x| x.get_cell_records(cell_id))
             .collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cell_records_fetch_order() {
+        let record =
+            |start: RangeT, weight: ProbT| Record::new_with_id(&(start..start + 100), weight);
+        let query_records = vec![
+            vec![
+                (0, record(10, 1.0)),
+                (3, record(40, 1.0)),
+                (4, record(50, 1.0)),
+            ],
+            vec![
+                (1, record(20, 2.0)),
+                (2, record(30, 2.0)),
+                (5, record(60, 2.0)),
+            ],
+            Vec::new(),
+        ];
+        let anchors = vec![vec![(0, 0.5), (1, 0.25), (2, 1.0)], Vec::new()];
+        let assay = AssayRecords::new(query_records, anchors, vec![2.0, 1.0]);
+
+        let records = assay.get_cell_records(0);
+        let starts: Vec<RangeT> = records.records().iter().map(|x| x.range().start).collect();
+        assert_eq!(starts, [10, 20, 30, 40, 50, 60]);
+        let weights: Vec<ProbT> = records.records().iter().map(|x| x.id()).collect();
+        assert_eq!(weights, [1.0, 1.0, 1.0, 1.0, 1.0, 1.0]);
+
+        assert!(assay.get_cell_records(1).records().is_empty());
+    }
+}
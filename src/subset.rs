@@ -0,0 +1,296 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::io::Write;
+use std::path::Path;
+use std::time::Instant;
+
+use clap::ArgMatches;
+use crate::file;
+use crate::hmm;
+use crate::manifest::{Manifest, RunRecord, MANIFEST_FILE};
+use crate::posterior::{self, CellPosterior, StatePosterior};
+
+/// 0-indexed states given as 1-indexed values on the command line,
+/// checked against the number of states of the input.
+fn get_states(
+    sub_m: &ArgMatches,
+    num_states: usize,
+) -> Result<Option<Vec<usize>>, Box<dyn Error>> {
+    match sub_m.values_of("states") {
+        Some(vals) => {
+            let mut states = Vec::new();
+            for val in vals {
+                let state = val.parse::<usize>()?;
+                if state == 0 || state > num_states {
+                    return Err(format!(
+                        "state {} out of range, states are 1 to {}",
+                        state, num_states
+                    )
+                    .into());
+                }
+                states.push(state - 1);
+            }
+            Ok(Some(states))
+        }
+        None => Ok(None),
+    }
+}
+
+/// number of states of the input, from its manifest or else from the
+/// files of its first chromosome.
+fn get_num_states(
+    in_path: &Path,
+    chrs: &[String],
+    manifest: &Option<Manifest>,
+) -> Result<usize, Box<dyn Error>> {
+    if let Some(manifest) = manifest {
+        return Ok(match manifest.marks.is_empty() {
+            true => manifest.num_states,
+            false => manifest.marks.len(),
+        });
+    }
+
+    let chr_path = match chrs.first() {
+        Some(chr_name) => in_path.join(chr_name),
+        None => return Err(format!("can't find chromosomes in {:?}", in_path).into()),
+    };
+    match chr_path.join("cells.txt").exists() {
+        true => Ok(posterior::get_state_files(&chr_path)?.last().copied().unwrap_or(0)),
+        false => match get_cell_files(&chr_path)?.first() {
+            Some(cell) => {
+                let cell_file = chr_path.join(format!("{}.bin", cell));
+                Ok(CellPosterior::from_path(&cell_file)?.num_states() as usize)
+            }
+            None => Err(format!("can't find cell files in {:?}", chr_path).into()),
+        },
+    }
+}
+
+/// cell names with a `<cell>.bin` file in a "long" chromosome directory.
+fn get_cell_files(chr_path: &Path) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut cells: Vec<String> = std::fs::read_dir(chr_path)?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter_map(|name| name.strip_suffix(".bin").map(|x| x.to_string()))
+        .collect();
+    cells.sort();
+
+    Ok(cells)
+}
+
+fn subset_short(
+    in_path: &Path,
+    out_path: &Path,
+    cells: &Option<Vec<String>>,
+    states: &Option<Vec<usize>>,
+) -> Result<usize, Box<dyn Error>> {
+    let chr_cells = posterior::read_cells(in_path.join("cells.txt"))?;
+    let cell_index: HashMap<&String, usize> =
+        chr_cells.iter().enumerate().map(|(i, x)| (x, i)).collect();
+
+    let cell_ids: Vec<usize> = match cells {
+        Some(cells) => {
+            let ids: Vec<usize> = cells
+                .iter()
+                .filter_map(|x| cell_index.get(x).copied())
+                .collect();
+            if ids.len() != cells.len() {
+                warn!(
+                    "{} of the {} requested cells are not present in {:?}",
+                    cells.len() - ids.len(),
+                    cells.len(),
+                    in_path
+                );
+            }
+            ids
+        }
+        None => (0..chr_cells.len()).collect(),
+    };
+
+    let mut cell_id_handle =
+        std::io::BufWriter::new(std::fs::File::create(out_path.join("cells.txt"))?);
+    for &cell_id in &cell_ids {
+        writeln!(cell_id_handle, "{}", chr_cells[cell_id])?;
+    }
+
+    let state_files = posterior::get_state_files(in_path)?;
+    let pbar = hmm::get_progress_bar(state_files.len());
+    for state in state_files {
+        if let Some(states) = states {
+            if !states.contains(&(state - 1)) {
+                continue;
+            }
+        }
+
+        let file_name = format!("{}.bin", state);
+        match StatePosterior::from_path(&in_path.join(&file_name))? {
+            Some(in_mat) => {
+                if in_mat.num_cells() != chr_cells.len() {
+                    return Err(format!(
+                        "{:?} has {} cells, cells.txt has {}",
+                        in_path.join(&file_name),
+                        in_mat.num_cells(),
+                        chr_cells.len()
+                    )
+                    .into());
+                }
+
+                let mut out_mat = StatePosterior::new(in_mat.num_bins());
+                for &cell_id in &cell_ids {
+                    let (probs, indices) = in_mat.get_cell(cell_id);
+                    out_mat.push_cell(probs, indices);
+                }
+                out_mat.write(out_path.join(&file_name))?;
+            }
+            None => {
+                std::fs::File::create(out_path.join(&file_name))?;
+            }
+        }
+        pbar.inc(1);
+    }
+    pbar.finish();
+
+    Ok(cell_ids.len())
+}
+
+fn subset_long(
+    in_path: &Path,
+    out_path: &Path,
+    cells: &Option<Vec<String>>,
+    states: &Option<Vec<usize>>,
+    num_states: usize,
+    written_cells: &mut Vec<String>,
+) -> Result<usize, Box<dyn Error>> {
+    let chr_cells = match cells {
+        Some(cells) => cells.clone(),
+        None => get_cell_files(in_path)?,
+    };
+
+    let pbar = hmm::get_progress_bar(chr_cells.len());
+    let mut num_cells = 0;
+    for cell in chr_cells {
+        pbar.inc(1);
+        let file_name = format!("{}.bin", cell);
+        let cell_file = in_path.join(&file_name);
+        if !cell_file.exists() {
+            warn!("can't find {:?}, skipping", cell_file);
+            continue;
+        }
+
+        let mut cell_posterior = CellPosterior::from_path(&cell_file)?;
+        if cell_posterior.num_states() as usize != num_states {
+            return Err(format!(
+                "{:?} has {} states, expected {}",
+                cell_file,
+                cell_posterior.num_states(),
+                num_states
+            )
+            .into());
+        }
+        if let Some(states) = states {
+            let mut keep_states = vec![false; num_states];
+            states.iter().for_each(|&x| keep_states[x] = true);
+            cell_posterior.retain_states(&keep_states);
+        }
+
+        file::write_binary(out_path.join(&file_name), cell_posterior.to_bytes())?;
+        written_cells.push(cell);
+        num_cells += 1;
+    }
+    pbar.finish();
+
+    Ok(num_cells)
+}
+
+pub fn callback(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let in_path = carina::file::file_path_from_clap(&sub_m, "in_directory")?;
+    info!("Found input directory path: {:?}", in_path);
+
+    let out_path = std::path::PathBuf::from(sub_m.value_of("out_directory").unwrap());
+    info!("Found output directory path: {:?}", out_path);
+
+    let cells = match sub_m.value_of("cells") {
+        Some(path) => {
+            let cells = posterior::read_cells(path.into())?;
+            info!("Subsetting to {} cells", cells.len());
+            Some(cells)
+        }
+        None => None,
+    };
+
+    let chrs: Vec<String> = match sub_m.values_of("chromosomes") {
+        Some(vals) => vals.map(|x| x.to_string()).collect(),
+        None => posterior::get_chromosomes(&in_path)?,
+    };
+    info!("Found total {} chromosomes", chrs.len());
+
+    let mut manifest = Manifest::from_dir(&in_path)?;
+    let num_states = get_num_states(&in_path, &chrs, &manifest)?;
+    let states = get_states(&sub_m, num_states)?;
+    if let Some(states) = &states {
+        info!("Subsetting to {} of the {} states", states.len(), num_states);
+    }
+
+    if let Some(manifest) = manifest.as_mut() {
+        if let Some(states) = &states {
+            manifest.states.retain(|x| states.contains(&(x - 1)));
+        }
+        manifest.runs.push(RunRecord::new("subset"));
+    }
+
+    let mut long_cells: Vec<String> = Vec::new();
+    for chr_name in chrs {
+        let chr_start = Instant::now();
+        let chr_in_path = in_path.join(&chr_name);
+        if !chr_in_path.is_dir() {
+            return Err(format!("can't find chromosome directory {:?}", chr_in_path).into());
+        }
+
+        let chr_out_path = out_path.join(&chr_name);
+        std::fs::create_dir_all(&chr_out_path)?;
+
+        info!("Working on {}", chr_name);
+        let num_cells = match chr_in_path.join("cells.txt").exists() {
+            true => subset_short(&chr_in_path, &chr_out_path, &cells, &states)?,
+            false => subset_long(
+                &chr_in_path,
+                &chr_out_path,
+                &cells,
+                &states,
+                num_states,
+                &mut long_cells,
+            )?,
+        };
+        info!("Wrote {} cells for {}", num_cells, chr_name);
+
+        if let Some(manifest) = manifest.as_mut() {
+            let num_bins = manifest.num_bins(&chr_name);
+            manifest
+                .runs
+                .last_mut()
+                .unwrap()
+                .add_chromosome(&chr_name, num_bins, num_cells, chr_start);
+        }
+    }
+
+    match manifest {
+        Some(manifest) => manifest.write(&out_path)?,
+        None => warn!("Can't find {} in {:?}", MANIFEST_FILE, in_path),
+    };
+
+    // "long" output has no per chromosome cell list, write the union
+    // so that the subset can be used as `--common_cells` for transform.
+    if !long_cells.is_empty() {
+        let mut seen: HashSet<String> = HashSet::new();
+        let mut cell_id_handle =
+            std::io::BufWriter::new(std::fs::File::create(out_path.join("cells.txt"))?);
+        for cell in long_cells {
+            if seen.insert(cell.clone()) {
+                writeln!(cell_id_handle, "{}", cell)?;
+            }
+        }
+    }
+
+    info!("All Done");
+    Ok(())
+}
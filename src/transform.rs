@@ -1,18 +1,25 @@
-use std::convert::TryInto;
 use std::error::Error;
-use std::io::Read;
 use std::io::Write;
 use std::sync::{mpsc, Arc};
+use std::time::Instant;
 
 use clap::ArgMatches;
 use crossbeam::queue::ArrayQueue;
-use indicatif::{ProgressBar, ProgressStyle};
 
 use crate::config::{CHR_LENS, CHR_LENS_SMALL};
 use crate::hmm;
+use crate::manifest::{Manifest, RunRecord, MANIFEST_FILE};
+use crate::posterior::{self, CellPosterior};
 
 pub fn callback(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
-    let common_cells = hmm::get_cells(&sub_m)?;
+    let in_path = carina::file::file_path_from_clap(&sub_m, "in_directory").unwrap();
+    info!("Found input directory path: {:?}", in_path);
+
+    // hmm records the cells it processed, use them if not given explicitly.
+    let common_cells = match sub_m.is_present("common_cells") {
+        true => hmm::get_cells(&sub_m)?,
+        false => posterior::read_cells(in_path.join("cells.txt"))?,
+    };
     let num_common_cells = common_cells.len();
     info!(
         "Found {} cells in common assay, first cell={}",
@@ -28,27 +35,49 @@ pub fn callback(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
     };
     info!("Found total {} chromosomes", chr_lens.len());
 
-    let in_path = carina::file::file_path_from_clap(&sub_m, "in_directory").unwrap();
-    info!("Found input directory path: {:?}", in_path);
+    let mut manifest = Manifest::from_dir(&in_path)?;
+    let num_states = match &manifest {
+        Some(manifest) => {
+            manifest.expect_format("long", &in_path)?;
+            match manifest.marks.is_empty() {
+                true => manifest.num_states,
+                false => manifest.marks.len(),
+            }
+        }
+        None => {
+            warn!(
+                "Can't find {} in {:?}, assuming {} states",
+                MANIFEST_FILE, in_path, num_states
+            );
+            num_states
+        }
+    };
+    if let Some(manifest) = manifest.as_mut() {
+        manifest.format = "short".to_string();
+        manifest.runs.push(RunRecord::new("transform"));
+    }
 
     let out_path = carina::file::file_path_from_clap(&sub_m, "out_directory").unwrap();
     info!("Found output directory path: {:?}", out_path);
 
+    if let Some(shard) = posterior::read_shard(&in_path)? {
+        info!("Found shard {}/{}", shard.0, shard.1);
+        std::fs::create_dir_all(&out_path)?;
+        std::fs::copy(
+            in_path.join(posterior::SHARD_FILE),
+            out_path.join(posterior::SHARD_FILE),
+        )?;
+    }
+
     info!("Starting to read");
     //(0..num_chrs).rev().for_each(|chr_id| {
     chrs.rev().for_each(|chr_id| {
+        let chr_start = Instant::now();
         let chr_name = format!("chr{}", chr_id+1);
         let num_bins = (chr_lens[chr_id] / 200) + 1;
         info!("Working on {}", chr_name);
 
-        let pbar = ProgressBar::new(num_common_cells as u64);
-        pbar.set_style(
-            ProgressStyle::default_bar()
-                .template(
-                    "{spinner:.red} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos:>7}/{len:7} {msg}",
-                )
-                .progress_chars("╢▌▌░╟"),
-        );
+        let pbar = hmm::get_progress_bar(num_common_cells);
 
         let q = Arc::new(ArrayQueue::<usize>::new(num_common_cells));
         (0..num_common_cells).for_each(|x| q.push(x).unwrap());
@@ -71,30 +100,17 @@ pub fn callback(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
                     match reader.pop() {
                         Some(cell_id) => {
                             let cell_file = arc_in_path.join(format!("{}.bin", arc_common_cells[cell_id]));
-                            let mut file_handle = carina::file::bufreader_from_filepath(cell_file).unwrap();
-
-                            let mut mat_u8_sizes = vec![0 as u8; 12];
-                            file_handle.read_exact(&mut mat_u8_sizes).expect("can't read sizes");
-                            let nnz: u32 = u32::from_le_bytes(mat_u8_sizes[0..4].try_into().unwrap());
-                            let _nrows: u32 = u32::from_le_bytes(mat_u8_sizes[4..8].try_into().unwrap());
-                            let _ncols: u32 = u32::from_le_bytes(mat_u8_sizes[8..12].try_into().unwrap());
-
-                            let mut indices = vec![0 as u8; nnz as usize * 4];
-                            let mut states = vec![0 as u8; nnz as usize];
-                            let mut probs = vec![0 as u8; nnz as usize];
-
-                            file_handle.read_exact(&mut probs).expect("can't read probs");
-                            file_handle.read_exact(&mut states).expect("can't read states");
-                            file_handle.read_exact(&mut indices).expect("can't read indices");
+                            let cell_posterior = CellPosterior::from_path(&cell_file).expect("can't read cell posterior");
+                            let (probs, states, indices) = (cell_posterior.probs(), cell_posterior.states(), cell_posterior.indices());
 
                             let states_size = states.len();
                             let mut state_indices = vec![Vec::<u8>::new(); num_states];
                             let mut state_probs = vec![Vec::new(); num_states];
-                            for (idx, state) in states.into_iter().rev().enumerate() {
+                            for (idx, &state) in states.iter().rev().enumerate() {
                                 let idx = states_size - idx - 1;
-                                let state: usize = u8::from_le(state) as usize;
+                                let state: usize = state as usize;
                                 state_probs[state].push(probs[idx]);
-                                state_indices[state].extend(&indices[idx*4..(idx+1)*4]);
+                                state_indices[state].extend(&indices[idx].to_le_bytes());
                             }
 
                             tx.send(Some((state_indices, state_probs, cell_id)))
@@ -158,6 +174,16 @@ pub fn callback(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
         })
         .unwrap(); //end crossbeam
         pbar.finish();
+
+        if let Some(manifest) = manifest.as_mut() {
+            manifest.runs.last_mut().unwrap().add_chromosome(
+                &chr_name,
+                num_bins as usize,
+                num_common_cells,
+                chr_start,
+            );
+            manifest.write(&out_path).unwrap();
+        }
     }); // end for loop over chromosomes
 
     info!("All Done");